@@ -107,11 +107,11 @@ fn criterion_benchmark(c: &mut Criterion) {
     // }
 
     {
-        let mut avr = AvrSimulator::new(
-            "atmega328p",
-            u32::MAX,
-            "examples/echo_spi_int/build/echo_spi_int.elf",
-        );
+        let mut avr = AvrSimulator::builder("atmega328p")
+            .frequency(u32::MAX)
+            .firmware("examples/echo_spi_int/build/echo_spi_int.elf")
+            .build()
+            .unwrap();
 
         c.bench_function("sim_communicate_spi interrupt", |b| b.iter(|| sim_communicate_spi(&mut avr)));
     }
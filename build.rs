@@ -21,7 +21,7 @@ fn main() -> Result<(), Error> {
     )?;
 
     // Generate code
-    prost_build::compile_protos(&["messages/request.proto"], &["messages/"])?;
+    prost_build::compile_protos(&["messages/request.proto", "messages/event.proto"], &["messages/"])?;
 
     Ok(())
 }
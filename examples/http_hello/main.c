@@ -12,6 +12,21 @@ volatile char buffer[BUFFER_SIZE];
 volatile int buffer_idx = 0;
 volatile int end_headers = 0;
 
+// Requests arrive as avr_net frames from the TCP gateway, addressed to the client that sent them,
+// and the response is framed for the same address so that only that client receives it
+enum frame_mode {
+    FRAME_ADDRESS_MSB,
+    FRAME_ADDRESS_LSB,
+    FRAME_LENGTH_MSB,
+    FRAME_LENGTH_LSB,
+    FRAME_DATA,
+};
+
+volatile enum frame_mode frame_mode = FRAME_ADDRESS_MSB;
+volatile uint16_t frame_address = 0;
+volatile uint16_t frame_remaining = 0;
+volatile uint16_t request_address = 0;
+
 void USART_Init(unsigned int ubrr) {
     UBRR0H = (unsigned char)(ubrr>>8);
     UBRR0L = (unsigned char)ubrr;
@@ -25,12 +40,19 @@ void USART_Transmit(char data) {
     UDR0 = data;
 }
 
-void send_http_response() {
+void send_http_response(uint16_t address) {
     char response[] = "HTTP/1.1 200 OK\r\n"
                       "Content-Length: 18\r\n"
                       "Content-Type: text/plain; charset=utf-8\r\n"
                       "\r\n"
                       "Hello from an AVR!";
+    uint16_t length = strlen(response) + 1;
+
+    USART_Transmit(address >> 8);
+    USART_Transmit(address & 0xFF);
+    USART_Transmit(length >> 8);
+    USART_Transmit(length & 0xFF);
+
     for (int i = 0; response[i]; i++) {
         USART_Transmit(response[i]);
     }
@@ -39,9 +61,16 @@ void send_http_response() {
     USART_Transmit(4);
 }
 
-// Interrupt service routine for UART Receive Complete
-ISR(USART_RX_vect) {
-    char received_char = UDR0; // Read received character from buffer
+void receive_char(char received_char) {
+    // A request from another client interrupts this one
+    if (buffer_idx > 0 && frame_address != request_address) {
+        memset((void *)buffer, 0, BUFFER_SIZE);
+        buffer_idx = 0;
+    }
+
+    if (buffer_idx == 0) {
+        request_address = frame_address;
+    }
 
     if (buffer_idx == 0 && received_char != 'G') {
         return;
@@ -64,6 +93,37 @@ ISR(USART_RX_vect) {
     }
 }
 
+// Interrupt service routine for UART Receive Complete
+ISR(USART_RX_vect) {
+    char c = UDR0; // Read received character from buffer
+
+    switch (frame_mode) {
+        case FRAME_ADDRESS_MSB:
+            frame_address = (uint8_t)c << 8;
+            frame_mode = FRAME_ADDRESS_LSB;
+            break;
+        case FRAME_ADDRESS_LSB:
+            frame_address |= (uint8_t)c;
+            frame_mode = FRAME_LENGTH_MSB;
+            break;
+        case FRAME_LENGTH_MSB:
+            frame_remaining = (uint8_t)c << 8;
+            frame_mode = FRAME_LENGTH_LSB;
+            break;
+        case FRAME_LENGTH_LSB:
+            frame_remaining |= (uint8_t)c;
+            frame_mode = frame_remaining > 0 ? FRAME_DATA : FRAME_ADDRESS_MSB;
+            break;
+        case FRAME_DATA:
+            receive_char(c);
+
+            if (--frame_remaining == 0) {
+                frame_mode = FRAME_ADDRESS_MSB;
+            }
+            break;
+    }
+}
+
 int main(void) {
     USART_Init(MYUBRR);
 
@@ -71,7 +131,7 @@ int main(void) {
         // If headers are complete, check if it's a GET request
         if (end_headers) {
             if (strncmp((const char *)buffer, "GET", 3) == 0) {
-                send_http_response();
+                send_http_response(request_address);
             }
 
             // Reset everything for the next request
@@ -1,53 +1,111 @@
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+// Frames sent to this address are delivered to every node
+pub(crate) const BROADCAST_ADDRESS: u16 = 0xffff;
+
+// The most data a frame can carry, limited by the receive buffer in comms.h
+pub(crate) const MAX_DATA_LENGTH: usize = 256;
+
+// Marks the start of a frame when using CRC framing
+const SYNC: [u8; 2] = [0xa5, 0x5a];
+
+/// How messages are laid out on the wire.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// Address, length and data, as implemented by `examples/sat_demo/comms.c`
+    #[default]
+    Legacy,
+
+    /// Sync bytes, address, length, header CRC, data and data CRC. CRCs are CRC-16/CCITT-FALSE and
+    /// like every other field are sent MSB first. The header CRC covers the address and length.
+    Crc,
+}
+
 #[derive(Debug, PartialEq)]
 enum AvrNetMode {
+    Sync0,
+    Sync1,
     AddressMsb,
     AddressLsb,
     LengthMsb,
     LengthLsb,
+    HeaderCrcMsb,
+    HeaderCrcLsb,
     Data,
+    DataCrcMsb,
+    DataCrcLsb,
+    // Discarding the data of a frame that was rejected, so that the next one is parsed correctly
+    Skip,
 }
 
 pub(crate) struct AvrNetState {
-    my_address: u16,
+    // Only messages for this address (or broadcasts) are returned, or all of them if None
+    my_address: Option<u16>,
+    framing: Framing,
+    // Abandon a partially received frame if the next byte takes longer than this to arrive
+    timeout: Option<Duration>,
+    last_rx_time: Option<Duration>,
     message_address: u16,
     length: u16,
+    crc: u16,
     index: usize,
-    data: [u8; 256],
+    data: [u8; MAX_DATA_LENGTH],
     mode: AvrNetMode,
+    frames_dropped: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct AvrNetMessage {
     pub(crate) address: u16,
     pub(crate) data: Vec<u8>,
 }
 
-impl TryFrom<AvrNetMessage> for Vec<u8> {
-    type Error = &'static str;
-
-    fn try_from(msg: AvrNetMessage) -> Result<Self, Self::Error> {
-        if msg.data.len() > u16::MAX as usize {
+impl AvrNetMessage {
+    pub(crate) fn encode(&self, framing: Framing) -> Result<Vec<u8>, &'static str> {
+        if self.data.len() > u16::MAX as usize {
             return Err("Invalid message length");
         }
 
+        let len: u16 = self.data.len() as u16;
+        let header = [
+            (self.address >> 8) as u8,
+            self.address as u8,
+            (len >> 8) as u8,
+            len as u8,
+        ];
+
         let mut data = Vec::new();
 
-        // Address
-        data.push((msg.address >> 8) as u8);
-        data.push(msg.address as u8);
+        if framing == Framing::Crc {
+            data.extend(SYNC);
+        }
 
-        // Length
-        let len: u16 = msg.data.len() as u16;
-        data.push((len >> 8) as u8);
-        data.push(len as u8);
+        data.extend(header);
 
-        // Data
-        data.extend(msg.data.iter());
+        if framing == Framing::Crc {
+            data.extend(crc16(&header).to_be_bytes());
+        }
+
+        data.extend(self.data.iter());
+
+        if framing == Framing::Crc {
+            data.extend(crc16(&self.data).to_be_bytes());
+        }
 
         Ok(data)
     }
 }
 
+impl TryFrom<AvrNetMessage> for Vec<u8> {
+    type Error = &'static str;
+
+    fn try_from(msg: AvrNetMessage) -> Result<Self, Self::Error> {
+        msg.encode(Framing::Legacy)
+    }
+}
+
 impl TryFrom<Vec<u8>> for AvrNetMessage {
     type Error = &'static str;
 
@@ -69,12 +127,12 @@ impl TryFrom<Vec<u8>> for AvrNetMessage {
         let len: u16 = ((data[2] as u16) << 8) | (data[3] as u16);
 
         // Data
-        msg.data = data[4..4 + len as usize].to_vec();
-
-        if msg.data.len() != len as usize {
+        if data.len() < 4 + len as usize {
             return Err("Invalid message length");
         }
 
+        msg.data = data[4..4 + len as usize].to_vec();
+
         Ok(msg)
     }
 }
@@ -82,25 +140,124 @@ impl TryFrom<Vec<u8>> for AvrNetMessage {
 impl AvrNetState {
     pub(crate) fn new(my_address: u16) -> AvrNetState {
         AvrNetState {
-            my_address,
+            my_address: Some(my_address),
+            framing: Framing::Legacy,
+            timeout: None,
+            last_rx_time: None,
             message_address: 0,
             length: 0,
+            crc: 0,
             index: 0,
-            data: [0; 256],
+            data: [0; MAX_DATA_LENGTH],
             mode: AvrNetMode::AddressMsb,
+            frames_dropped: 0,
+        }
+    }
+
+    // Parse messages regardless of who they are addressed to, e.g. to route them
+    pub(crate) fn any_address() -> AvrNetState {
+        AvrNetState {
+            my_address: None,
+            ..AvrNetState::new(0)
+        }
+    }
+
+    pub(crate) fn with_framing(mut self, framing: Framing) -> AvrNetState {
+        self.framing = framing;
+        self.reset();
+        self
+    }
+
+    pub(crate) fn with_timeout(mut self, timeout: Option<Duration>) -> AvrNetState {
+        self.timeout = timeout;
+        self
+    }
+
+    // How many frames were discarded because they were malformed, corrupted or interrupted
+    pub(crate) fn frames_dropped(&self) -> u64 {
+        self.frames_dropped
+    }
+
+    fn is_for_me(&self) -> bool {
+        match self.my_address {
+            Some(my_address) => self.message_address == my_address || self.message_address == BROADCAST_ADDRESS,
+            None => true,
+        }
+    }
+
+    fn start_mode(&self) -> AvrNetMode {
+        match self.framing {
+            Framing::Legacy => AvrNetMode::AddressMsb,
+            Framing::Crc => AvrNetMode::Sync0,
         }
     }
 
     fn reset(&mut self) {
         self.message_address = 0;
         self.length = 0;
+        self.crc = 0;
         self.index = 0;
-        self.mode = AvrNetMode::AddressMsb;
-        self.data = [0; 256];
+        self.mode = self.start_mode();
+        self.data = [0; MAX_DATA_LENGTH];
+    }
+
+    fn drop_frame(&mut self) {
+        self.frames_dropped += 1;
+        self.reset();
+    }
+
+    fn finish_frame(&mut self) -> Option<AvrNetMessage> {
+        let message = if self.is_for_me() {
+            Some(AvrNetMessage {
+                address: self.message_address,
+                data: self.data[0..self.length as usize].to_vec(),
+            })
+        } else {
+            None
+        };
+
+        self.reset();
+        message
+    }
+
+    fn header(&self) -> [u8; 4] {
+        let address = self.message_address.to_be_bytes();
+        let length = self.length.to_be_bytes();
+        [address[0], address[1], length[0], length[1]]
+    }
+
+    // Like `rx`, but gives up on a partial frame if the byte arrives too long after the previous one
+    pub(crate) fn rx_at(&mut self, c: u8, now: Duration) -> Option<AvrNetMessage> {
+        if let (Some(timeout), Some(last_rx_time)) = (self.timeout, self.last_rx_time) {
+            if now.saturating_sub(last_rx_time) > timeout {
+                match self.mode {
+                    // A lone sync byte isn't a frame yet, and a skipped frame has been counted
+                    // already when it was rejected
+                    AvrNetMode::Sync1 | AvrNetMode::Skip => self.reset(),
+                    _ if self.mode == self.start_mode() => (),
+                    _ => self.drop_frame(),
+                }
+            }
+        }
+
+        self.last_rx_time = Some(now);
+        self.rx(c)
     }
 
     pub(crate) fn rx(&mut self, c: u8) -> Option<AvrNetMessage> {
         match self.mode {
+            AvrNetMode::Sync0 => {
+                if c == SYNC[0] {
+                    self.mode = AvrNetMode::Sync1;
+                }
+            }
+            AvrNetMode::Sync1 => {
+                if c == SYNC[1] {
+                    self.mode = AvrNetMode::AddressMsb;
+                } else if c != SYNC[0] {
+                    self.mode = AvrNetMode::Sync0;
+                }
+            }
             AvrNetMode::AddressMsb => {
                 self.message_address = (c as u16) << 8;
                 self.mode = AvrNetMode::AddressLsb;
@@ -115,34 +272,99 @@ impl AvrNetState {
             }
             AvrNetMode::LengthLsb => {
                 self.length |= c as u16;
-                self.mode = AvrNetMode::Data;
+
+                if self.framing == Framing::Crc {
+                    self.mode = AvrNetMode::HeaderCrcMsb;
+                    return None;
+                }
+
+                if self.length as usize > MAX_DATA_LENGTH {
+                    // Keep in step with the sender by discarding the data that follows
+                    self.frames_dropped += 1;
+                    self.mode = AvrNetMode::Skip;
+                } else if self.length == 0 {
+                    return self.finish_frame();
+                } else {
+                    self.mode = AvrNetMode::Data;
+                }
+            }
+            AvrNetMode::HeaderCrcMsb => {
+                self.crc = (c as u16) << 8;
+                self.mode = AvrNetMode::HeaderCrcLsb;
+            }
+            AvrNetMode::HeaderCrcLsb => {
+                self.crc |= c as u16;
+
+                // A bad header can't be trusted for the length, so look for the next sync instead
+                if self.crc != crc16(&self.header()) || self.length as usize > MAX_DATA_LENGTH {
+                    self.drop_frame();
+                } else if self.length == 0 {
+                    self.mode = AvrNetMode::DataCrcMsb;
+                } else {
+                    self.mode = AvrNetMode::Data;
+                }
             }
             AvrNetMode::Data => {
                 self.data[self.index] = c;
                 self.index += 1;
 
                 if self.index == self.length as usize {
-                    self.mode = AvrNetMode::AddressMsb;
-                    if self.message_address == self.my_address {
-                        let data = self.data[0..self.length as usize].to_vec();
-                        let message = AvrNetMessage {
-                            address: self.message_address,
-                            data,
-                        };
-                        self.reset();
-                        return Some(message);
+                    if self.framing == Framing::Crc {
+                        self.mode = AvrNetMode::DataCrcMsb;
+                    } else {
+                        return self.finish_frame();
                     }
                 }
             }
+            AvrNetMode::DataCrcMsb => {
+                self.crc = (c as u16) << 8;
+                self.mode = AvrNetMode::DataCrcLsb;
+            }
+            AvrNetMode::DataCrcLsb => {
+                self.crc |= c as u16;
+
+                if self.crc == crc16(&self.data[0..self.length as usize]) {
+                    return self.finish_frame();
+                }
+
+                self.drop_frame();
+            }
+            AvrNetMode::Skip => {
+                self.index += 1;
+
+                if self.index == self.length as usize {
+                    self.reset();
+                }
+            }
         }
 
         None
     }
 }
 
+// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+
+    for b in data {
+        crc ^= (*b as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::avr_net::AvrNetMessage;
+    use std::time::Duration;
+    use crate::avr_net::{AvrNetMessage, AvrNetState, Framing, BROADCAST_ADDRESS, crc16};
 
     #[test]
     fn message_from_vec() {
@@ -163,4 +385,129 @@ mod tests {
         let data: Vec<u8> = message.try_into().unwrap();
         assert_eq!(data, vec![0, 1, 0, 2, 4, 2]);
     }
+
+    #[test]
+    fn state_skips_messages_for_others() {
+        let mut state = AvrNetState::new(1);
+        let stream = vec![0, 2, 0, 1, 9, 0, 1, 0, 2, 4, 2];
+
+        let messages: Vec<AvrNetMessage> = stream.into_iter().filter_map(|c| state.rx(c)).collect();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].address, 1);
+        assert_eq!(messages[0].data, vec![4, 2]);
+    }
+
+    #[test]
+    fn state_accepts_broadcasts() {
+        let mut state = AvrNetState::new(1);
+        let stream: Vec<u8> = AvrNetMessage { address: BROADCAST_ADDRESS, data: vec![7] }.try_into().unwrap();
+
+        let messages: Vec<AvrNetMessage> = stream.into_iter().filter_map(|c| state.rx(c)).collect();
+
+        assert_eq!(messages.len(), 1);
+    }
+
+    fn parse_all(state: &mut AvrNetState, stream: &[u8]) -> Vec<AvrNetMessage> {
+        stream.iter().filter_map(|c| state.rx(*c)).collect()
+    }
+
+    #[test]
+    fn crc_matches_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn legacy_rejects_oversize_frames_without_losing_sync() {
+        let mut state = AvrNetState::new(1);
+        let mut stream = vec![0, 1, 0x01, 0x01];
+        stream.extend([0; 0x101]);
+        stream.extend([0, 1, 0, 1, 7]);
+
+        let messages = parse_all(&mut state, &stream);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].data, vec![7]);
+        assert_eq!(state.frames_dropped(), 1);
+    }
+
+    #[test]
+    fn crc_framing_round_trip() {
+        let mut state = AvrNetState::new(1).with_framing(Framing::Crc);
+        let stream = AvrNetMessage { address: 1, data: vec![4, 2] }.encode(Framing::Crc).unwrap();
+
+        let messages = parse_all(&mut state, &stream);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].data, vec![4, 2]);
+    }
+
+    #[test]
+    fn crc_framing_resyncs_after_lost_byte() {
+        let mut state = AvrNetState::new(1).with_framing(Framing::Crc);
+        let frame = AvrNetMessage { address: 1, data: vec![4, 2] }.encode(Framing::Crc).unwrap();
+
+        // Lose a byte from the middle of the first frame
+        let mut stream = frame.clone();
+        stream.remove(5);
+        stream.extend(&frame);
+
+        let messages = parse_all(&mut state, &stream);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(state.frames_dropped(), 1);
+    }
+
+    #[test]
+    fn crc_framing_drops_corrupted_data() {
+        let mut state = AvrNetState::new(1).with_framing(Framing::Crc);
+        let mut stream = AvrNetMessage { address: 1, data: vec![4, 2] }.encode(Framing::Crc).unwrap();
+        stream[8] ^= 0x10;
+
+        assert!(parse_all(&mut state, &stream).is_empty());
+        assert_eq!(state.frames_dropped(), 1);
+    }
+
+    #[test]
+    fn timeout_abandons_partial_frame() {
+        let mut state = AvrNetState::new(1).with_timeout(Some(Duration::from_millis(1)));
+
+        // Half of a frame, then a whole one after a pause
+        assert!(state.rx_at(0, Duration::ZERO).is_none());
+        assert!(state.rx_at(1, Duration::ZERO).is_none());
+
+        let later = Duration::from_millis(5);
+        let messages: Vec<AvrNetMessage> = [0, 1, 0, 1, 7].iter().filter_map(|c| state.rx_at(*c, later)).collect();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(state.frames_dropped(), 1);
+    }
+
+    #[test]
+    fn timeout_counts_each_abandoned_frame_once() {
+        let timeout = Some(Duration::from_millis(1));
+        let later = Duration::from_millis(5);
+
+        // An oversize frame, cut off while its data is being skipped
+        let mut state = AvrNetState::new(1).with_timeout(timeout);
+
+        for c in [0, 1, 0x01, 0x01, 0] {
+            assert!(state.rx_at(c, Duration::ZERO).is_none());
+        }
+
+        let messages: Vec<AvrNetMessage> = [0, 1, 0, 1, 7].iter().filter_map(|c| state.rx_at(*c, later)).collect();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(state.frames_dropped(), 1);
+
+        // A stray sync byte
+        let mut state = AvrNetState::new(1).with_framing(Framing::Crc).with_timeout(timeout);
+        assert!(state.rx_at(0xa5, Duration::ZERO).is_none());
+
+        let frame = AvrNetMessage { address: 1, data: vec![4, 2] }.encode(Framing::Crc).unwrap();
+        let messages: Vec<AvrNetMessage> = frame.iter().filter_map(|c| state.rx_at(*c, later)).collect();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(state.frames_dropped(), 0);
+    }
 }
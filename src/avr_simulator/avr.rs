@@ -6,6 +6,7 @@ use simavr_ffi as ffi;
 
 use super::state::AvrState;
 use super::ioctl::IoCtl;
+use super::builder::AvrSimulatorError;
 
 #[derive(Debug)]
 pub struct Avr {
@@ -13,12 +14,12 @@ pub struct Avr {
 }
 
 impl Avr {
-    pub fn new(mcu: &str, frequency: u32) -> Self {
-        let c_mcu = CString::new(mcu).unwrap();
+    pub fn new(mcu: &str, frequency: u32) -> Result<Self, AvrSimulatorError> {
+        let c_mcu = CString::new(mcu).map_err(|_| AvrSimulatorError::UnknownMcu(mcu.to_string()))?;
 
         // Safety: `c_mcu` points to a valid C-style string
         let inner = unsafe { ffi::avr_make_mcu_by_name(c_mcu.as_ptr()) };
-        let inner = NonNull::new(inner).unwrap_or_else(|| panic!("Unknown AVR: {}", mcu));
+        let inner = NonNull::new(inner).ok_or_else(|| AvrSimulatorError::UnknownMcu(mcu.to_string()))?;
 
         let mut this = Self { inner };
 
@@ -27,7 +28,7 @@ impl Avr {
         let status = unsafe { ffi::avr_init(this.inner.as_ptr()) };
 
         if status != 0 {
-            panic!("avr_init() failed (status={})", status);
+            return Err(AvrSimulatorError::InitFailed { status });
         }
 
         // Safety: `inner` points to a valid `avr_t`; nothing else is writing
@@ -36,7 +37,30 @@ impl Avr {
             this.inner.as_mut().frequency = frequency;
         }
 
-        this
+        Ok(this)
+    }
+
+    /// Returns whether simavr knows an MCU of given name.
+    pub fn is_known(mcu: &str) -> bool {
+        let c_mcu = match CString::new(mcu) {
+            Ok(c_mcu) => c_mcu,
+            Err(_) => return false,
+        };
+
+        // Safety: `c_mcu` points to a valid C-style string
+        let inner = unsafe { ffi::avr_make_mcu_by_name(c_mcu.as_ptr()) };
+
+        if inner.is_null() {
+            return false;
+        }
+
+        // Safety: `inner` has just been allocated by simavr and hasn't been
+        // initialized, so there's nothing else to release
+        unsafe {
+            libc::free(inner as *mut _);
+        }
+
+        true
     }
 
     pub fn cycle(&self) -> u64 {
@@ -45,12 +69,62 @@ impl Avr {
         unsafe { self.inner.as_ref().cycle }
     }
 
+    /// Returns the underlying `avr_t`, for IRQ callbacks that need to know
+    /// the current cycle.
+    pub fn as_ptr(&self) -> NonNull<ffi::avr_t> {
+        self.inner
+    }
+
     pub fn frequency(&self) -> u32 {
         // Safety: `inner` points to a valid `avr_t`; nothing else is writing
         // there at the moment, as guarded by `&mut self` on `fn run()`
         unsafe { self.inner.as_ref().frequency }
     }
 
+    pub fn pc(&self) -> u32 {
+        // Safety: `inner` points to a valid `avr_t`; nothing else is writing
+        // there at the moment, as guarded by `&mut self` on `fn run()`
+        unsafe { self.inner.as_ref().pc }
+    }
+
+    pub fn state(&self) -> AvrState {
+        // Safety: `inner` points to a valid `avr_t`; nothing else is writing
+        // there at the moment, as guarded by `&mut self` on `fn run()`
+        AvrState::from_ffi(unsafe { self.inner.as_ref().state })
+    }
+
+    pub fn reset(&mut self) {
+        // Safety: `inner` points to a valid `avr_t`; nothing else is writing
+        // there at the moment, as guarded by `&mut self` here
+        unsafe {
+            ffi::avr_reset(self.inner.as_ptr());
+        }
+    }
+
+    pub fn data(&self, address: u16) -> u8 {
+        // Safety: `inner` points to a valid `avr_t`, whose `data` covers the
+        // whole data space, including the I/O registers callers are after
+        unsafe { *self.inner.as_ref().data.add(address as usize) }
+    }
+
+    pub fn set_data(&mut self, address: u16, value: u8) {
+        // Safety: as above, and nothing else is writing there at the moment,
+        // as guarded by `&mut self` here
+        unsafe {
+            *self.inner.as_mut().data.add(address as usize) = value;
+        }
+    }
+
+    pub fn set_fuses(&mut self, fuses: &[u8]) {
+        // Safety: `inner` points to a valid `avr_t`; nothing else is writing
+        // there at the moment, as guarded by `&mut self` here
+        let fuse = unsafe { &mut self.inner.as_mut().fuse };
+
+        for (dst, src) in fuse.iter_mut().zip(fuses) {
+            *dst = *src;
+        }
+    }
+
     pub fn run(&mut self) -> AvrState {
         // Safety: `inner` points to a valid `avr_t`; nothing else is writing
         // there at the moment, as guarded by `&mut self` here
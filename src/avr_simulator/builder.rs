@@ -0,0 +1,215 @@
+use std::{collections::HashMap, error, fmt, path::{Path, PathBuf}};
+use super::{adc::*, avr::*, firmware::*, logging, port::*, spi::*, uart::*, AvrSimulator};
+
+/// Reasons why an [`AvrSimulator`] couldn't be built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AvrSimulatorError {
+    /// simavr doesn't know an MCU of this name.
+    UnknownMcu(String),
+
+    /// simavr failed to initialize the MCU.
+    InitFailed { status: i32 },
+
+    /// No firmware has been given to the builder.
+    MissingFirmware,
+
+    /// Firmware couldn't be read, e.g. because the file is missing or isn't
+    /// an ELF.
+    BadFirmware { path: String, status: i32 },
+
+    /// EEPROM image doesn't fit into the EEPROM.
+    EepromTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for AvrSimulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownMcu(mcu) => write!(f, "unknown MCU: {}", mcu),
+            Self::InitFailed { status } => write!(f, "avr_init() failed (status = {})", status),
+            Self::MissingFirmware => write!(f, "no firmware given"),
+            Self::BadFirmware { path, status } => {
+                write!(f, "couldn't load firmware from {} (status = {})", path, status)
+            }
+            Self::EepromTooLarge { size, max } => {
+                write!(f, "EEPROM data too large ({} > {})", size, max)
+            }
+        }
+    }
+}
+
+impl error::Error for AvrSimulatorError {}
+
+/// Configures and creates an [`AvrSimulator`].
+///
+/// ```no_run
+/// # use mycochip::avr_simulator::AvrSimulator;
+/// let avr = AvrSimulator::builder("atmega328p")
+///     .frequency(16_000_000)
+///     .firmware("firmware.elf")
+///     .build()?;
+/// # Ok::<(), mycochip::avr_simulator::AvrSimulatorError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct AvrSimulatorBuilder {
+    mcu: String,
+    frequency: u32,
+    firmware: Option<PathBuf>,
+    eeprom: Option<Vec<u8>>,
+    fuses: Option<[u8; 3]>,
+    adc: bool,
+    spis: bool,
+    uarts: bool,
+}
+
+impl AvrSimulatorBuilder {
+    /// Creates a builder for given MCU, running at 16 MHz with all of its
+    /// peripherals attached.
+    pub fn new(mcu: &str) -> Self {
+        Self {
+            mcu: mcu.to_string(),
+            frequency: 16_000_000,
+            firmware: None,
+            eeprom: None,
+            fuses: None,
+            adc: true,
+            spis: true,
+            uarts: true,
+        }
+    }
+
+    /// Sets the clock frequency, in Hz.
+    pub fn frequency(mut self, frequency: u32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Sets the ELF file to flash; required.
+    pub fn firmware(mut self, path: impl AsRef<Path>) -> Self {
+        self.firmware = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the initial contents of the EEPROM, overriding the ELF's
+    /// `.eeprom` section.
+    pub fn eeprom(mut self, data: &[u8]) -> Self {
+        self.eeprom = Some(data.to_vec());
+        self
+    }
+
+    /// Sets the low, high and extended fuse bytes, overriding the ELF's
+    /// `.fuse` section.
+    pub fn fuses(mut self, low: u8, high: u8, extended: u8) -> Self {
+        self.fuses = Some([low, high, extended]);
+        self
+    }
+
+    /// Sets whether to attach the ADC; without it, analog pins can't be set.
+    pub fn adc(mut self, attach: bool) -> Self {
+        self.adc = attach;
+        self
+    }
+
+    /// Sets whether to attach the SPIs; without them, bytes sent over SPI are
+    /// lost.
+    pub fn spis(mut self, attach: bool) -> Self {
+        self.spis = attach;
+        self
+    }
+
+    /// Sets whether to attach the UARTs; without them, bytes sent over UART
+    /// are lost.
+    pub fn uarts(mut self, attach: bool) -> Self {
+        self.uarts = attach;
+        self
+    }
+
+    pub fn build(self) -> Result<AvrSimulator, AvrSimulatorError> {
+        logging::init();
+
+        let firmware_path = self.firmware.as_ref().ok_or(AvrSimulatorError::MissingFirmware)?;
+
+        // Firmware is read before the AVR is created so that a bad file
+        // leaves nothing behind
+        let mut firmware = Firmware::new().load_elf(firmware_path)?;
+
+        if let Some(eeprom) = &self.eeprom {
+            firmware = firmware.write_eeprom(eeprom)?;
+        }
+
+        let mut avr = Avr::new(&self.mcu, self.frequency)?;
+
+        // Safety: `avr` lives as long as `adc`
+        let adc = match self.adc {
+            true => unsafe { Adc::new(&mut avr) },
+            false => None,
+        };
+
+        firmware.flash_to(&mut avr);
+
+        if let Some(fuses) = &self.fuses {
+            avr.set_fuses(fuses);
+        }
+
+        // Initialize SPIs.
+        //
+        // Note that we have to do that eagerly instead of on-demand, because
+        // we have to register for the input IRQ notifications as soon as
+        // possible as not to miss any bytes.
+        let mut spis = HashMap::new();
+
+        if self.spis {
+            for spi_id in 0..8 {
+                // Safety: `avr` lives as long as `spi`
+                let spi = unsafe { Spi::new(spi_id, &mut avr) };
+
+                if let Some(spi) = spi {
+                    spis.insert(spi_id, spi);
+                } else {
+                    break;
+                }
+            }
+        }
+
+        // Initialize UARTs.
+        //
+        // As with SPIs, we have to do that eagerly a
+        let mut uarts = HashMap::new();
+
+        if self.uarts {
+            for uart_id in 0..8 {
+                #[allow(clippy::char_lit_as_u8)]
+                let uart_id = (('0' as u8) + uart_id) as char;
+
+                // Safety: `avr` lives as long as `uart`
+                let uart = unsafe { Uart::new(uart_id, &mut avr) };
+
+                if let Some(uart) = uart {
+                    uarts.insert(uart_id, uart);
+                } else {
+                    break;
+                }
+            }
+        }
+
+        // Initialize ports.
+        //
+        // Parts differ in which ports they have (e.g. ATmega328P has no port
+        // A), so every port simavr might know about is tried.
+        let mut ports = HashMap::new();
+
+        for port_id in 'A'..='L' {
+            // Safety: `avr` lives as long as `port`
+            if let Some(port) = unsafe { Port::new(port_id, &mut avr) } {
+                ports.insert(port_id, port);
+            }
+        }
+
+        Ok(AvrSimulator {
+            avr,
+            adc,
+            spis,
+            uarts,
+            ports,
+        })
+    }
+}
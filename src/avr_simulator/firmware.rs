@@ -1,6 +1,7 @@
 use simavr_ffi as ffi;
 use std::{alloc, ffi::CString, path::Path, ptr::NonNull};
 use super::avr::Avr;
+use super::builder::AvrSimulatorError;
 
 pub struct Firmware {
     ptr: NonNull<ffi::elf_firmware_t>,
@@ -25,33 +26,29 @@ impl Firmware {
         Self { ptr }
     }
 
-    pub fn load_elf(self, path: impl AsRef<Path>) -> Self {
+    pub fn load_elf(self, path: impl AsRef<Path>) -> Result<Self, AvrSimulatorError> {
         let path = path.as_ref().display().to_string();
 
         // Unwrap-safety: Paths cannot contain null-terminators, so a string
         // we've got from `.display().to_string()` cannot either
-        let c_path = CString::new(path).unwrap();
+        let c_path = CString::new(path.clone()).unwrap();
 
         // Safety: `self.ptr` points at a valid, zeroed instance of
         // `elf_firmware_t`; `c_path` points at a valid `CString`
         let status = unsafe { ffi::elf_read_firmware(c_path.as_ptr(), self.ptr.as_ptr()) };
 
         if status != 0 {
-            panic!(
-                "Couldn't load firmware from: {} (status = {})",
-                c_path.into_string().unwrap(),
-                status
-            );
+            return Err(AvrSimulatorError::BadFirmware { path, status });
         }
 
-        self
+        Ok(self)
     }
 
-    pub fn write_eeprom(self, data: &[u8]) -> Firmware {
+    pub fn write_eeprom(self, data: &[u8]) -> Result<Self, AvrSimulatorError> {
         const EESIZE: usize = 1024;
 
         if data.len() > EESIZE {
-            panic!("EEPROM data too large ({} > {})", data.len(), EESIZE);
+            return Err(AvrSimulatorError::EepromTooLarge { size: data.len(), max: EESIZE });
         }
 
         unsafe {
@@ -66,7 +63,7 @@ impl Firmware {
             std::ptr::copy_nonoverlapping(data.as_ptr(), fw.eeprom, data.len());
         }
 
-        self
+        Ok(self)
     }
 
     pub fn flash_to(self, avr: &mut Avr) {
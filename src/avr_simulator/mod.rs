@@ -5,23 +5,26 @@
 //! over simavr, this interface is limited and curated (i.e. not as generic as
 //! simavr itself).
 //!
-//! See: [`AvrSimulator::new()`].
+//! See: [`AvrSimulator::builder()`].
 
 pub(crate) mod state;
 mod adc;
 mod avr;
+mod builder;
 mod duration;
 mod firmware;
 mod ioctl;
 mod logging;
+mod pin_monitor;
 mod port;
+mod reset;
 mod spi;
 mod uart;
 
-use self::{adc::*, avr::*, firmware::*, port::*, spi::*, uart::*};
-use std::{collections::HashMap, path::Path};
+use self::{adc::*, avr::*, pin_monitor::PinMonitor, port::*, spi::*, uart::*};
+use std::collections::HashMap;
 
-pub use self::{duration::*, state::*};
+pub use self::{builder::*, duration::*, pin_monitor::Waveform, port::PinChange, reset::*, state::*};
 
 /// Bare-bones wrapper for simavr.
 #[derive(Debug)]
@@ -30,77 +33,18 @@ pub struct AvrSimulator {
     adc: Option<Adc>,
     spis: HashMap<u8, Spi>,
     uarts: HashMap<char, Uart>,
+    ports: HashMap<char, Port>,
 }
 
 impl AvrSimulator {
-    pub fn new(mcu: &str, frequency: u32, firmware: impl AsRef<Path>, eeprom: Option<&[u8]>) -> Self {
-        logging::init();
-
-        let mut avr = Avr::new(mcu, frequency);
-
-        // Safety: `avr` lives as long as `adc`
-        let adc = unsafe { Adc::new(&mut avr) };
-
-        if eeprom.is_some() {
-            Firmware::new()
-                .load_elf(firmware)
-                .write_eeprom(eeprom.unwrap())
-                .flash_to(&mut avr);
-        } else {
-            Firmware::new().load_elf(firmware).flash_to(&mut avr);
-        }
+    /// Returns whether simavr can simulate an MCU of given name.
+    pub fn supports_mcu(mcu: &str) -> bool {
+        Avr::is_known(mcu)
+    }
 
-        // Initialize SPIs.
-        //
-        // Note that we have to do that eagerly instead of on-demand, because
-        // we have to register for the input IRQ notifications as soon as
-        // possible as not to miss any bytes.
-        let spis = {
-            let mut spis = HashMap::new();
-
-            for spi_id in 0..8 {
-                // Safety: `avr` lives as long as `spi`
-                let spi = unsafe { Spi::new(spi_id, &mut avr) };
-
-                if let Some(spi) = spi {
-                    spis.insert(spi_id, spi);
-                } else {
-                    break;
-                }
-            }
-
-            spis
-        };
-
-        // Initialize UARTs.
-        //
-        // As with SPIs, we have to do that eagerly a
-        let uarts = {
-            let mut uarts = HashMap::new();
-
-            for uart_id in 0..8 {
-                #[allow(clippy::char_lit_as_u8)]
-                let uart_id = (('0' as u8) + uart_id) as char;
-
-                // Safety: `avr` lives as long as `uart`
-                let uart = unsafe { Uart::new(uart_id, &mut avr) };
-
-                if let Some(uart) = uart {
-                    uarts.insert(uart_id, uart);
-                } else {
-                    break;
-                }
-            }
-
-            uarts
-        };
-
-        Self {
-            avr,
-            adc,
-            spis,
-            uarts,
-        }
+    /// Starts building a simulator for given MCU, e.g. `"atmega328p"`.
+    pub fn builder(mcu: &str) -> AvrSimulatorBuilder {
+        AvrSimulatorBuilder::new(mcu)
     }
 
     /// Executes a single instruction.
@@ -125,6 +69,39 @@ impl AvrSimulator {
         StepOutcome { state, tt }
     }
 
+    /// Resets the AVR, leaving MCUSR the way given kind of reset would, so
+    /// that firmware can tell why it has been restarted.
+    pub fn reset(&mut self, kind: ResetKind) {
+        let mcusr = kind.mcusr(self.avr.data(MCUSR));
+
+        self.avr.reset();
+        self.avr.set_data(MCUSR, mcusr);
+
+        for port in self.ports.values_mut() {
+            port.sync(&mut self.avr);
+        }
+    }
+
+    /// Returns the address of the next instruction, in bytes.
+    pub fn pc(&self) -> u32 {
+        self.avr.pc()
+    }
+
+    /// Returns how many cycles have been executed since the AVR was started.
+    pub fn cycle(&self) -> u64 {
+        self.avr.cycle()
+    }
+
+    /// Returns the state the AVR was left in by the last step.
+    pub fn state(&self) -> AvrState {
+        self.avr.state()
+    }
+
+    /// Returns how much simulated time has passed since the AVR was started.
+    pub fn elapsed(&self) -> AvrDuration {
+        AvrDuration::new(self.avr.frequency(), self.avr.cycle())
+    }
+
     pub fn read_spi(&mut self, id: u8) -> Option<u8> {
         self.spi(id).read()
     }
@@ -137,10 +114,47 @@ impl AvrSimulator {
         self.uart(id).read()
     }
 
+    /// Like [`Self::read_uart()`], but also returns when the byte was sent,
+    /// since the AVR was started.
+    pub fn read_uart_timed(&mut self, id: char) -> Option<(AvrDuration, u8)> {
+        self.uart(id).read_timed()
+    }
+
     pub fn write_uart(&mut self, id: char, byte: u8) {
         self.uart(id).write(byte)
     }
 
+    /// Returns whether the AVR has the given UART, e.g. `'0'` for UART0.
+    pub fn has_uart(&self, id: char) -> bool {
+        self.uarts.contains_key(&id)
+    }
+
+    /// Returns the ports current AVR has, e.g. `['B', 'C', 'D']`.
+    pub fn ports(&self) -> Vec<char> {
+        let mut ports: Vec<char> = self.ports.keys().copied().collect();
+        ports.sort();
+        ports
+    }
+
+    /// Returns whether the AVR has given pin, e.g. `('B', 5)` for PB5.
+    pub fn has_pin(&self, port: char, pin: u8) -> bool {
+        pin < 8 && self.ports.contains_key(&port)
+    }
+
+    /// Returns whether the AVR has an ADC attached, so that analog pins can
+    /// be set.
+    pub fn has_adc(&self) -> bool {
+        self.adc.is_some()
+    }
+
+    /// Returns the pin changes since the last call, oldest first; every
+    /// change is seen, however short the pulse.
+    pub fn take_pin_changes(&mut self) -> Vec<PinChange> {
+        let mut changes: Vec<PinChange> = self.ports.values_mut().flat_map(Port::take_changes).collect();
+        changes.sort_by_key(|change| change.at.as_cycles());
+        changes
+    }
+
     pub fn get_digital_pin(&mut self, port: char, pin: u8) -> bool {
         Port::get_pin(&mut self.avr, port, pin)
     }
@@ -149,6 +163,42 @@ impl AvrSimulator {
         Port::set_pin(&mut self.avr, port, pin, high);
     }
 
+    /// Starts recording every edge of given pin, for [`Self::waveform()`];
+    /// returns `false` if current AVR doesn't have this pin.
+    pub fn monitor_pin(&mut self, port: char, pin: u8) -> bool {
+        if !self.has_pin(port, pin) {
+            return false;
+        }
+
+        self.ports.get_mut(&port).unwrap().monitor(pin);
+        true
+    }
+
+    /// Returns the edges of a monitored pin recorded so far, oldest first.
+    pub fn pin_edges(&self, port: char, pin: u8) -> Option<&[PinChange]> {
+        self.pin_monitor(port, pin).map(PinMonitor::edges)
+    }
+
+    /// Returns what a monitored pin did during the last `window` of simulated
+    /// time.
+    pub fn waveform(&self, port: char, pin: u8, window: AvrDuration) -> Option<Waveform> {
+        let frequency = self.avr.frequency();
+        let end = self.avr.cycle();
+        let window = (window.as_secs_f64() * frequency as f64).round() as u64;
+
+        self.pin_monitor(port, pin)
+            .map(|monitor| monitor.waveform(frequency, end.saturating_sub(window), end))
+    }
+
+    /// Forgets the edges recorded for a monitored pin so far.
+    pub fn clear_pin_edges(&mut self, port: char, pin: u8) {
+        let cycle = self.avr.cycle();
+
+        if let Some(monitor) = self.ports.get_mut(&port).and_then(|port| port.monitor_of_mut(pin)) {
+            monitor.clear(cycle);
+        }
+    }
+
     pub fn set_analog_pin(&mut self, pin: u8, voltage: u32) {
         self.adc
             .as_mut()
@@ -156,6 +206,10 @@ impl AvrSimulator {
             .set_voltage(pin, voltage);
     }
 
+    fn pin_monitor(&self, port: char, pin: u8) -> Option<&PinMonitor> {
+        self.ports.get(&port)?.monitor_of(pin)
+    }
+
     fn spi(&mut self, id: u8) -> &mut Spi {
         self.spis
             .get_mut(&id)
@@ -0,0 +1,312 @@
+use super::duration::AvrDuration;
+use super::port::PinChange;
+
+// Edges kept per monitored pin; once there are more, the oldest ones are
+// forgotten, as if the monitor had been started later
+const MAX_EDGES: usize = 1024 * 1024;
+
+/// Keeps the history of a digital pin's changes, as recorded by its
+/// [`super::port::Port`], so that what the pin did can be measured.
+#[derive(Debug)]
+pub struct PinMonitor {
+    /// Cycle since which edges have been recorded.
+    started: u64,
+
+    /// Level of the pin at `started`.
+    initial: bool,
+
+    edges: Vec<PinChange>,
+}
+
+impl PinMonitor {
+    pub fn new(level: bool, cycle: u64) -> Self {
+        Self {
+            started: cycle,
+            initial: level,
+            edges: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, change: PinChange) {
+        if self.edges.len() == MAX_EDGES {
+            // Dropping half at once keeps this cheap for a pin that's busy
+            // for long
+            let last_dropped = self.edges[MAX_EDGES / 2 - 1];
+
+            self.started = last_dropped.at.as_cycles();
+            self.initial = last_dropped.high;
+            self.edges.drain(..MAX_EDGES / 2);
+        }
+
+        self.edges.push(change);
+    }
+
+    /// Returns the edges recorded so far, oldest first.
+    pub fn edges(&self) -> &[PinChange] {
+        &self.edges
+    }
+
+    /// Forgets the edges recorded so far, e.g. to keep memory in check during
+    /// long runs.
+    pub fn clear(&mut self, cycle: u64) {
+        self.initial = self.level();
+        self.started = cycle;
+        self.edges.clear();
+    }
+
+    /// Returns what the pin did between two cycles; the window is cut short at
+    /// the start if the pin wasn't being monitored yet.
+    pub fn waveform(&self, clock_frequency: u32, from: u64, to: u64) -> Waveform {
+        let from = from.max(self.started).min(to);
+
+        let initial = self.edges.iter()
+            .take_while(|edge| edge.at.as_cycles() <= from)
+            .last()
+            .map(|edge| edge.high)
+            .unwrap_or(self.initial);
+
+        let edges = self.edges.iter()
+            .filter(|edge| edge.at.as_cycles() > from && edge.at.as_cycles() <= to)
+            .copied()
+            .collect();
+
+        Waveform::new(clock_frequency, from, to, initial, edges)
+    }
+
+    fn level(&self) -> bool {
+        self.edges.last().map_or(self.initial, |edge| edge.high)
+    }
+}
+
+/// What a digital pin did during a window of time, with helpers to measure
+/// and assert on it, e.g. for PWM:
+///
+/// ```no_run
+/// # use mycochip::avr_simulator::{AvrDuration, AvrSimulator};
+/// #
+/// let mut avr = AvrSimulator::builder("atmega328p").firmware("pwm.elf").build().unwrap();
+///
+/// avr.monitor_pin('D', 6);
+///
+/// for _ in 0..1_000_000 {
+///     avr.step();
+/// }
+///
+/// let waveform = avr.waveform('D', 6, AvrDuration::new(16_000_000, 0).add_millis(20)).unwrap();
+///
+/// waveform.assert_frequency(976.5, 0.01);
+/// waveform.assert_duty_cycle(0.25, 0.01);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Waveform {
+    clock_frequency: u32,
+    start: u64,
+    end: u64,
+    initial: bool,
+    edges: Vec<PinChange>,
+}
+
+impl Waveform {
+    pub(crate) fn new(clock_frequency: u32, start: u64, end: u64, initial: bool, edges: Vec<PinChange>) -> Self {
+        Self {
+            clock_frequency,
+            start,
+            end,
+            initial,
+            edges,
+        }
+    }
+
+    /// Returns how long the window is.
+    pub fn duration(&self) -> AvrDuration {
+        AvrDuration::new(self.clock_frequency, self.end - self.start)
+    }
+
+    /// Returns the level of the pin at the start of the window.
+    pub fn initial(&self) -> bool {
+        self.initial
+    }
+
+    /// Returns the edges within the window, oldest first.
+    pub fn edges(&self) -> &[PinChange] {
+        &self.edges
+    }
+
+    pub fn rising_edges(&self) -> usize {
+        self.edges.iter().filter(|edge| edge.high).count()
+    }
+
+    pub fn falling_edges(&self) -> usize {
+        self.edges.iter().filter(|edge| !edge.high).count()
+    }
+
+    /// Returns how long the pin was high during the window.
+    pub fn high_time(&self) -> AvrDuration {
+        let mut level = self.initial;
+        let mut since = self.start;
+        let mut high = 0;
+
+        for edge in &self.edges {
+            if level {
+                high += edge.at.as_cycles() - since;
+            }
+
+            level = edge.high;
+            since = edge.at.as_cycles();
+        }
+
+        if level {
+            high += self.end - since;
+        }
+
+        AvrDuration::new(self.clock_frequency, high)
+    }
+
+    /// Returns the share of the window the pin was high for, from 0.0 to 1.0.
+    pub fn duty_cycle(&self) -> f64 {
+        if self.end == self.start {
+            return if self.initial { 1.0 } else { 0.0 };
+        }
+
+        self.high_time().as_cycles() as f64 / (self.end - self.start) as f64
+    }
+
+    /// Returns the frequency of the signal in Hz, measured between the first
+    /// and the last rising edge; `None` if there are fewer than two.
+    pub fn frequency(&self) -> Option<f64> {
+        let mut rising = self.edges.iter().filter(|edge| edge.high);
+        let first = rising.next()?;
+        let (periods, last) = rising.fold((0, first), |(periods, _), edge| (periods + 1, edge));
+
+        let cycles = last.at.as_cycles() - first.at.as_cycles();
+
+        if periods == 0 || cycles == 0 {
+            return None;
+        }
+
+        Some(periods as f64 * self.clock_frequency as f64 / cycles as f64)
+    }
+
+    /// Returns the widths of the complete high (or low) pulses within the
+    /// window, i.e. leaving out ones that started before it or hadn't ended
+    /// by its end.
+    pub fn pulse_widths(&self, high: bool) -> Vec<AvrDuration> {
+        self.edges.windows(2)
+            .filter(|pair| pair[0].high == high && pair[1].high != high)
+            .map(|pair| AvrDuration::new(self.clock_frequency, pair[1].at.as_cycles() - pair[0].at.as_cycles()))
+            .collect()
+    }
+
+    /// Panics unless the signal's frequency is within `tolerance` (relative,
+    /// e.g. 0.01 for 1%) of `hz`.
+    #[track_caller]
+    pub fn assert_frequency(&self, hz: f64, tolerance: f64) {
+        match self.frequency() {
+            Some(frequency) if (frequency - hz).abs() <= hz * tolerance => (),
+            Some(frequency) => panic!("expected a frequency of {} Hz, got {:.3} Hz", hz, frequency),
+            None => panic!("expected a frequency of {} Hz, got {} edges within {}", hz, self.edges.len(), self.duration()),
+        }
+    }
+
+    /// Panics unless the duty cycle is within `tolerance` (absolute, e.g.
+    /// 0.01 for one percentage point) of `duty_cycle`.
+    #[track_caller]
+    pub fn assert_duty_cycle(&self, duty_cycle: f64, tolerance: f64) {
+        let actual = self.duty_cycle();
+
+        if (actual - duty_cycle).abs() > tolerance {
+            panic!("expected a duty cycle of {:.3}, got {:.3}", duty_cycle, actual);
+        }
+    }
+
+    /// Panics unless there is at least one complete high (or low) pulse, and
+    /// all of them are between `min` and `max` long.
+    #[track_caller]
+    pub fn assert_pulse_widths(&self, high: bool, min: AvrDuration, max: AvrDuration) {
+        let widths = self.pulse_widths(high);
+        let level = if high { "high" } else { "low" };
+
+        if widths.is_empty() {
+            panic!("expected {} pulses, got none within {}", level, self.duration());
+        }
+
+        for width in widths {
+            if width.as_secs_f64() < min.as_secs_f64() || width.as_secs_f64() > max.as_secs_f64() {
+                panic!("expected {} pulses between {} and {}, got one of {}", level, min, max, width);
+            }
+        }
+    }
+
+    /// Panics unless the pin changed exactly `count` times.
+    #[track_caller]
+    pub fn assert_edge_count(&self, count: usize) {
+        if self.edges.len() != count {
+            panic!("expected {} edges, got {} within {}", count, self.edges.len(), self.duration());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(cycle: u64, high: bool) -> PinChange {
+        PinChange { port: 'D', pin: 6, high, at: AvrDuration::new(1_000_000, cycle) }
+    }
+
+    // 1 kHz at 1 MHz, high for the first quarter of each period
+    fn pwm() -> Waveform {
+        let edges = (0..10)
+            .flat_map(|period| [edge(100 + period * 1000, true), edge(350 + period * 1000, false)])
+            .collect();
+
+        Waveform::new(1_000_000, 0, 10_000, false, edges)
+    }
+
+    #[test]
+    fn measures_pwm() {
+        let waveform = pwm();
+
+        assert_eq!(waveform.frequency(), Some(1000.0));
+        assert_eq!(waveform.duty_cycle(), 0.25);
+        assert_eq!(waveform.rising_edges(), 10);
+        assert_eq!(waveform.pulse_widths(true), vec![AvrDuration::new(1_000_000, 250); 10]);
+        assert_eq!(waveform.pulse_widths(false).len(), 9);
+
+        waveform.assert_frequency(1000.0, 0.001);
+        waveform.assert_duty_cycle(0.25, 0.001);
+        waveform.assert_edge_count(20);
+        waveform.assert_pulse_widths(true, AvrDuration::new(1_000_000, 240), AvrDuration::new(1_000_000, 260));
+    }
+
+    #[test]
+    fn counts_levels_from_the_start_of_the_window() {
+        let waveform = Waveform::new(1_000_000, 0, 1000, true, vec![edge(600, false)]);
+
+        assert_eq!(waveform.high_time().as_cycles(), 600);
+        assert_eq!(waveform.frequency(), None);
+        assert!(waveform.pulse_widths(true).is_empty());
+    }
+
+    #[test]
+    fn oldest_edges_are_forgotten_past_the_cap() {
+        let mut monitor = PinMonitor::new(false, 0);
+
+        for n in 1..=MAX_EDGES as u64 + 1 {
+            monitor.record(edge(n, n % 2 == 1));
+        }
+
+        assert_eq!(monitor.edges().len(), MAX_EDGES / 2 + 1);
+
+        // The window starts where the last forgotten edge left the pin
+        let waveform = monitor.waveform(1_000_000, 0, MAX_EDGES as u64 + 1);
+        assert!(!waveform.initial());
+        assert_eq!(waveform.duration().as_cycles(), MAX_EDGES as u64 / 2 + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a duty cycle of 0.500, got 0.250")]
+    fn assertions_panic_with_what_was_measured() {
+        pwm().assert_duty_cycle(0.5, 0.01);
+    }
+}
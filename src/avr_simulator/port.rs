@@ -1,11 +1,112 @@
+use std::{collections::VecDeque, ptr::NonNull};
 use simavr_ffi as ffi;
 use super::ioctl::IoCtl;
 use super::avr::Avr;
+use super::duration::AvrDuration;
+use super::pin_monitor::PinMonitor;
+
+// Changes kept per port until they're taken, so that a busy pin nobody is
+// watching can't eat up memory
+const MAX_CHANGES: usize = 16 * 1024;
+
+/// A change of a digital pin's level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinChange {
+    pub port: char,
+    pub pin: u8,
+
+    /// Level the pin changed to.
+    pub high: bool,
+
+    /// When the pin changed, since the AVR was started.
+    pub at: AvrDuration,
+}
 
 /// Provides access to simavr's digital pins.
-pub struct Port;
+#[derive(Debug)]
+pub struct Port {
+    state: NonNull<PortState>,
+}
 
 impl Port {
+    /// Initializes the subsystem; returns `None` if current AVR doesn't have
+    /// this port.
+    ///
+    /// # Safety
+    ///
+    /// - Because this function registers IRQ notifications, the object
+    ///   returned from here must be kept alive for at least as long as `avr`.
+    pub unsafe fn new(id: char, avr: &mut Avr) -> Option<Self> {
+        let ioctl = IoCtl::IoPortGetIrq { port: id };
+        let pin0 = avr.try_io_getirq(ioctl, 0)?;
+        let levels = Self::get_port(avr, id)?;
+
+        let state = NonNull::from(Box::leak(Box::new(PortState {
+            id,
+            avr: avr.as_ptr(),
+            pin0,
+            levels,
+            changes: VecDeque::new(),
+            monitors: Default::default(),
+        })));
+
+        // Every pin has its own IRQ, raised with the pin's level; they follow
+        // each other, so the callback can tell the pin by its IRQ
+        for pin in 0..8 {
+            // Safety: The callback matches the pin's IRQ
+            Avr::irq_register_notify(avr.io_getirq(ioctl, pin), Some(Self::on_pin_change), state.as_ptr());
+        }
+
+        Some(Self { state })
+    }
+
+    /// Returns the changes since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<PinChange> {
+        self.borrow_mut().changes.drain(..).collect()
+    }
+
+    /// Starts keeping the history of given pin, if it isn't kept already, and
+    /// returns it.
+    pub fn monitor(&mut self, pin: u8) -> &mut PinMonitor {
+        let state = self.borrow_mut();
+        let level = state.levels & (1 << pin) > 0;
+
+        // Safety: `avr` outlives the port, as required by `Self::new()`
+        let cycle = unsafe { state.avr.as_ref().cycle };
+
+        state.monitors[pin as usize].get_or_insert_with(|| PinMonitor::new(level, cycle))
+    }
+
+    /// Returns the history of given pin, if it's being kept.
+    pub fn monitor_of(&self, pin: u8) -> Option<&PinMonitor> {
+        self.borrow().monitors.get(pin as usize)?.as_ref()
+    }
+
+    /// Like [`Self::monitor_of()`], but mutable.
+    pub fn monitor_of_mut(&mut self, pin: u8) -> Option<&mut PinMonitor> {
+        self.borrow_mut().monitors.get_mut(pin as usize)?.as_mut()
+    }
+
+    /// Records changes that happened without simavr raising IRQs for them,
+    /// e.g. when the AVR has been reset.
+    pub fn sync(&mut self, avr: &mut Avr) {
+        let levels = match Self::get_port(avr, self.borrow_mut().id) {
+            Some(levels) => levels,
+            None => return,
+        };
+
+        let at = AvrDuration::new(avr.frequency(), avr.cycle());
+        let state = self.borrow_mut();
+
+        for pin in 0..8 {
+            let high = levels & (1 << pin) > 0;
+
+            if high != (state.levels & (1 << pin) > 0) {
+                state.record(pin, high, at);
+            }
+        }
+    }
+
     pub fn set_pin(avr: &mut Avr, port: char, pin: u8, high: bool) {
         let irq = avr
             .try_io_getirq(IoCtl::IoPortGetIrq { port }, pin as u32)
@@ -18,6 +119,13 @@ impl Port {
     }
 
     pub fn get_pin(avr: &mut Avr, port: char, pin: u8) -> bool {
+        let port_value = Self::get_port(avr, port)
+            .unwrap_or_else(|| panic!("Current AVR doesn't have pin P{}{}", port, pin));
+
+        port_value & (1 << pin) > 0
+    }
+
+    fn get_port(avr: &mut Avr, port: char) -> Option<u8> {
         let mut state = ffi::avr_ioport_state_t {
             _bitfield_align_1: Default::default(),
             _bitfield_1: Default::default(),
@@ -29,11 +137,122 @@ impl Port {
         let status = unsafe { avr.ioctl(IoCtl::IoPortGetState { port }, &mut state) };
 
         if status == -1 {
-            panic!("Current AVR doesn't have pin P{}{}", port, pin);
+            return None;
+        }
+
+        Some(state._bitfield_1.get(7, 8) as u8)
+    }
+
+    fn borrow(&self) -> &PortState {
+        // Safety: `state` points to a valid object; callbacks only write there
+        // while the AVR runs, which requires `&mut` on the simulator
+        unsafe { self.state.as_ref() }
+    }
+
+    fn borrow_mut(&mut self) -> &mut PortState {
+        // Safety: `state` points to a valid object; nothing else is writing
+        // there at the moment, as guarded by `&mut self` here and on
+        // `Avr::run()`
+        unsafe { self.state.as_mut() }
+    }
+
+    unsafe extern "C" fn on_pin_change(
+        irq: NonNull<ffi::avr_irq_t>,
+        value: u32,
+        mut state: NonNull<PortState>,
+    ) {
+        let state = state.as_mut();
+        let pin = irq.as_ptr().offset_from(state.pin0.as_ptr()) as u8;
+        let high = value != 0;
+
+        // simavr raises pin IRQs whenever the port is written, not only when
+        // the pin actually changes
+        if high == (state.levels & (1 << pin) > 0) {
+            return;
+        }
+
+        let avr = state.avr.as_ref();
+        let at = AvrDuration::new(avr.frequency, avr.cycle);
+
+        state.record(pin, high, at);
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        // Safety: This pointer was obtained by creating a box and leaking it,
+        // and this function is called at most once
+        unsafe {
+            drop(Box::from_raw(self.state.as_ptr()));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PortState {
+    id: char,
+    avr: NonNull<ffi::avr_t>,
+
+    /// IRQ of the first pin; the others follow it.
+    pin0: NonNull<ffi::avr_irq_t>,
+
+    /// Level of each pin after its last change.
+    levels: u8,
+
+    changes: VecDeque<PinChange>,
+
+    /// Histories of the pins being monitored.
+    monitors: [Option<PinMonitor>; 8],
+}
+
+impl PortState {
+    fn record(&mut self, pin: u8, high: bool, at: AvrDuration) {
+        if high {
+            self.levels |= 1 << pin;
+        } else {
+            self.levels &= !(1 << pin);
+        }
+
+        let change = PinChange {
+            port: self.id,
+            pin,
+            high,
+            at,
+        };
+
+        if let Some(monitor) = &mut self.monitors[pin as usize] {
+            monitor.record(change);
         }
 
-        let port = state._bitfield_1.get(7, 8) as u8;
+        if self.changes.len() == MAX_CHANGES {
+            self.changes.pop_front();
+        }
+
+        self.changes.push_back(change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_recorded_and_capped() {
+        let mut state = PortState {
+            id: 'B',
+            avr: NonNull::dangling(),
+            pin0: NonNull::dangling(),
+            levels: 0,
+            changes: VecDeque::new(),
+            monitors: Default::default(),
+        };
+
+        for n in 0..MAX_CHANGES as u64 + 3 {
+            state.record(5, n % 2 == 0, AvrDuration::new(16_000_000, n));
+        }
 
-        port & (1 << pin) > 0
+        assert_eq!(state.levels, 1 << 5);
+        assert_eq!(state.changes.len(), MAX_CHANGES);
+        assert_eq!(state.changes[0], PinChange { port: 'B', pin: 5, high: false, at: AvrDuration::new(16_000_000, 3) });
     }
 }
@@ -0,0 +1,54 @@
+/// Address of MCUSR (MCUCSR on older parts) in the data space; it's the same
+/// on the ATmega and ATtiny families that simavr supports.
+pub(crate) const MCUSR: u16 = 0x54;
+
+const PORF: u8 = 1 << 0;
+const EXTRF: u8 = 1 << 1;
+const WDRF: u8 = 1 << 3;
+
+/// Cause of a reset, as reported to the firmware through MCUSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Supply voltage has just come up.
+    PowerOn,
+
+    /// Something pulled the RESET pin low.
+    External,
+
+    /// Watchdog timer has expired.
+    Watchdog,
+}
+
+impl ResetKind {
+    /// Returns MCUSR as the hardware leaves it after this kind of reset, given
+    /// its value before the reset.
+    ///
+    /// Like on the real thing, flags other than PORF accumulate until firmware
+    /// clears them.
+    pub(crate) fn mcusr(self, previous: u8) -> u8 {
+        match self {
+            Self::PowerOn => PORF,
+            Self::External => previous | EXTRF,
+            Self::Watchdog => previous | WDRF,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_accumulate_until_power_on() {
+        let mcusr = ResetKind::PowerOn.mcusr(0xff);
+        assert_eq!(mcusr, PORF);
+
+        let mcusr = ResetKind::External.mcusr(mcusr);
+        assert_eq!(mcusr, PORF | EXTRF);
+
+        let mcusr = ResetKind::Watchdog.mcusr(mcusr);
+        assert_eq!(mcusr, PORF | EXTRF | WDRF);
+
+        assert_eq!(ResetKind::PowerOn.mcusr(mcusr), PORF);
+    }
+}
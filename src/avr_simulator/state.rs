@@ -1,4 +1,5 @@
 use simavr_ffi as ffi;
+use std::fmt;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AvrState {
@@ -12,6 +13,23 @@ pub enum AvrState {
     Crashed,
 }
 
+impl fmt::Display for AvrState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Limbo => "limbo",
+            Self::Stopped => "stopped",
+            Self::Running => "running",
+            Self::Sleeping => "sleeping",
+            Self::Step => "step",
+            Self::StepDone => "step done",
+            Self::Done => "done",
+            Self::Crashed => "crashed",
+        };
+
+        f.write_str(name)
+    }
+}
+
 impl AvrState {
     pub(crate) fn from_ffi(val: i32) -> Self {
         match val as u32 {
@@ -1,6 +1,7 @@
 use std::{collections::VecDeque, ptr::NonNull};
 use simavr_ffi as ffi;
 use super::avr::Avr;
+use super::duration::AvrDuration;
 use super::ioctl::IoCtl;
 
 /// Provides access to simavr's UARTs.
@@ -50,7 +51,7 @@ impl Uart {
         // we can get notified when AVR sends something through this UART.
 
         let ioctl = IoCtl::UartGetIrq { uart: id };
-        let state = NonNull::from(Box::leak(Default::default()));
+        let state = NonNull::from(Box::leak(Box::new(UartState::new(avr))));
 
         // Safety: All of callbacks match the expected IRQs
         Avr::irq_register_notify(
@@ -77,6 +78,12 @@ impl Uart {
     }
 
     pub fn read(&mut self) -> Option<u8> {
+        self.read_timed().map(|(_, byte)| byte)
+    }
+
+    /// Like [`Self::read()`], but also returns when AVR sent the byte, since it
+    /// was started.
+    pub fn read_timed(&mut self) -> Option<(AvrDuration, u8)> {
         // Safety: We're releasing the borrow right-away
         unsafe { self.borrow_mut() }.rx.pop_front()
     }
@@ -127,7 +134,10 @@ impl Uart {
         value: u32,
         mut state: NonNull<UartState>,
     ) {
-        state.as_mut().rx.push_back(value as u8);
+        let state = state.as_mut();
+        let avr = state.avr.as_ref();
+
+        state.rx.push_back((AvrDuration::new(avr.frequency, avr.cycle), value as u8));
     }
 
     unsafe extern "C" fn on_xon(_: NonNull<ffi::avr_irq_t>, _: u32, mut state: NonNull<UartState>) {
@@ -157,20 +167,24 @@ impl Drop for Uart {
 
 #[derive(Debug)]
 struct UartState {
+    avr: NonNull<ffi::avr_t>,
+
     /// Queue of bytes scheduled to be sent into AVR.
     tx: VecDeque<u8>,
 
-    /// Queue of bytes retrieved from AVR, pending to be read by the simulator.
-    rx: VecDeque<u8>,
+    /// Queue of bytes retrieved from AVR, pending to be read by the simulator,
+    /// along with when AVR sent them.
+    rx: VecDeque<(AvrDuration, u8)>,
 
     /// When true, AVR is ready to retrieve the next UART byte; AVR toggles this
     /// value on and off as we flush the next bytes.
     xon: bool,
 }
 
-impl Default for UartState {
-    fn default() -> Self {
+impl UartState {
+    fn new(avr: &mut Avr) -> Self {
         Self {
+            avr: avr.as_ptr(),
             tx: Default::default(),
             rx: Default::default(),
             xon: true,
@@ -1,4 +1,12 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
+
+fn set_arg() -> Arg {
+    Arg::new("set")
+        .long("set")
+        .action(ArgAction::Append)
+        .value_name("PATH=VALUE")
+        .help("Override a setting of the configuration file, like devices.main.frequency=8MHz")
+}
 
 pub fn build_cli() -> Command {
     Command::new("mycochip")
@@ -7,7 +15,43 @@ pub fn build_cli() -> Command {
             .about("Bring up a network of devices in a given configuration")
             .arg(Arg::new("config-file")
                 .help("Configuration file")
-                .required(false)))
+                .required(false))
+            .arg(set_arg()))
+        .subcommand(Command::new("check")
+            .about("Check a configuration file without bringing up the network")
+            .arg(Arg::new("config-file")
+                .help("Configuration file")
+                .required(false))
+            .arg(set_arg()))
+        .subcommand(Command::new("run")
+            .about("Run a network without listening on any ports, for a bounded time, and print what the devices sent")
+            .arg(Arg::new("config-file")
+                .help("Configuration file")
+                .required(true))
+            .arg(Arg::new("duration")
+                .long("duration")
+                .help("Stop after this much simulated time, like 500ms or 2s"))
+            .arg(Arg::new("until-done")
+                .long("until-done")
+                .action(ArgAction::SetTrue)
+                .help("Stop once every device has finished or crashed"))
+            .arg(Arg::new("until-uart")
+                .long("until-uart")
+                .help("Stop once any device has sent this text"))
+            .arg(Arg::new("output-dir")
+                .long("output-dir")
+                .help("Write what each device sent to NAME.log in this directory instead of stdout"))
+            .arg(set_arg()))
+        .subcommand(Command::new("test")
+            .about("Run a scenario against a network, failing if any of its steps do")
+            .arg(Arg::new("scenario-file")
+                .help("Scenario file")
+                .required(true))
+            .arg(Arg::new("headless")
+                .long("headless")
+                .action(ArgAction::SetTrue)
+                .help("Don't listen on any ports, leaving out gateways"))
+            .arg(set_arg()))
         .subcommand(Command::new("list")
             .about("List running machines"))
         .subcommand(Command::new("pin")
@@ -31,4 +75,83 @@ pub fn build_cli() -> Command {
             .arg(Arg::new("node")
                 .help("Node name")
                 .required(true)))
+        .subcommand(Command::new("add")
+            .about("Add a device to the running network")
+            .arg(Arg::new("node")
+                .help("Node name")
+                .required(true))
+            .arg(Arg::new("device-file")
+                .help("YAML file describing the device, like an entry in the config file")
+                .required(true)))
+        .subcommand(Command::new("remove")
+            .about("Remove a device from the running network")
+            .arg(Arg::new("node")
+                .help("Node name")
+                .required(true)))
+        .subcommand(Command::new("connect")
+            .about("Connect two nodes in the running network")
+            .arg(Arg::new("node")
+                .help("Node name")
+                .required(true))
+            .arg(Arg::new("peer")
+                .help("Peer node name")
+                .required(true))
+            .arg(Arg::new("one-way")
+                .long("one-way")
+                .action(ArgAction::SetTrue)
+                .help("Only carry data from the node to the peer")))
+        .subcommand(Command::new("disconnect")
+            .about("Disconnect two nodes in the running network")
+            .arg(Arg::new("node")
+                .help("Node name")
+                .required(true))
+            .arg(Arg::new("peer")
+                .help("Peer node name")
+                .required(true))
+            .arg(Arg::new("one-way")
+                .long("one-way")
+                .action(ArgAction::SetTrue)
+                .help("Only stop data flowing from the node to the peer")))
+        .subcommand(Command::new("impair")
+            .about("Impair the link between two nodes in the running network")
+            .arg(Arg::new("node")
+                .help("Node name")
+                .required(true))
+            .arg(Arg::new("peer")
+                .help("Peer node name")
+                .required(true))
+            .arg(Arg::new("drop")
+                .long("drop")
+                .value_parser(clap::value_parser!(f64))
+                .help("Probability that a byte is lost"))
+            .arg(Arg::new("bit-flip")
+                .long("bit-flip")
+                .value_parser(clap::value_parser!(f64))
+                .help("Probability that each bit is inverted"))
+            .arg(Arg::new("latency")
+                .long("latency")
+                .value_parser(clap::value_parser!(u64))
+                .help("Delay in microseconds of simulated time"))
+            .arg(Arg::new("jitter")
+                .long("jitter")
+                .value_parser(clap::value_parser!(u64))
+                .help("Maximum random delay in microseconds added to the latency"))
+            .arg(Arg::new("bandwidth")
+                .long("bandwidth")
+                .value_parser(clap::value_parser!(u64))
+                .help("Maximum bits per second"))
+            .arg(Arg::new("partition")
+                .long("partition")
+                .action(ArgAction::Append)
+                .help("START_MS:DURATION_MS window, relative to now, during which the link is cut")))
+        .subcommand(Command::new("reset")
+            .about("Reset a device in the running network")
+            .arg(Arg::new("node")
+                .help("Node name")
+                .required(true))
+            .arg(Arg::new("kind")
+                .long("kind")
+                .value_parser(["external", "power-on", "watchdog"])
+                .default_value("external")
+                .help("Cause of the reset, as the firmware sees it in MCUSR")))
 }
@@ -1,9 +1,18 @@
 use prost::Message;
 
+pub const REQUEST_PORT: i32 = 6711;
+
+// Version of messages/event.proto that published events follow
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
 pub mod request {
     include!(concat!(env!("OUT_DIR"), "/mycochip.request.rs"));
 }
 
+pub mod event {
+    include!(concat!(env!("OUT_DIR"), "/mycochip.event.rs"));
+}
+
 pub fn serialize_request(req: &request::Request) -> Vec<u8> {
     let mut buf = Vec::new();
     buf.reserve(req.encoded_len());
@@ -16,11 +25,24 @@ pub fn deserialize_request(buf: &[u8]) -> Result<request::Request, prost::Decode
     request::Request::decode(buf)
 }
 
+pub fn serialize_event(event: &event::Event) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.reserve(event.encoded_len());
+
+    event.encode(&mut buf).unwrap();
+    buf
+}
+
+pub fn deserialize_event(buf: &[u8]) -> Result<event::Event, prost::DecodeError> {
+    event::Event::decode(buf)
+}
+
 pub fn send_request(req: &request::Request) -> Result<zmq::Message, zmq::Error> {
     let context = zmq::Context::new();
     let requester = context.socket(zmq::REQ).unwrap();
 
-    assert!(requester.connect("tcp://localhost:6723").is_ok());
+    let request_address = format!("tcp://localhost:{}", REQUEST_PORT);
+    assert!(requester.connect(request_address.as_str()).is_ok());
 
     let msg_bytes = serialize_request(req);
     requester.send(msg_bytes, 0).unwrap();
@@ -1,23 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io;
-use std::path::{Path};
-use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::Error;
+use serde_yaml::{Mapping, Value};
+use crate::avr_net::Framing;
+use crate::avr_simulator::AvrSimulator;
+use crate::link::Impairment;
+use crate::network::DEFAULT_BUS_BAUD;
+use crate::templates;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct MycochipConfig {
-    pub devices: std::collections::HashMap<String, Device>,
+    pub devices: HashMap<String, Device>,
+
+    // Seeds the randomness of link impairments so that runs are reproducible
+    #[serde(default)]
+    pub seed: u64,
+
+    #[serde(default = "Vec::new")]
+    pub links: Vec<LinkConfig>,
+
+    // Settings of the shared buses devices are attached to, by bus name
+    #[serde(default)]
+    pub buses: HashMap<String, BusConfig>,
+
+    #[serde(default)]
+    pub routing: Routing,
+
+    // How avr_net frames are laid out, both when routing and at the gateway
+    #[serde(default)]
+    pub framing: Framing,
+
+    // Partially received frames are abandoned after this much simulated time without a byte
+    pub frame_timeout_us: Option<u64>,
+
+    // Streams events to browser dashboards as JSON and takes commands from them
+    pub websocket: Option<WebSocketConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub port: u16,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Device {
+#[serde(deny_unknown_fields)]
+pub struct BusConfig {
+    // Bit rate of the bus, which decides how long each byte occupies it and so which
+    // transmissions overlap
+    #[serde(default = "default_bus_baud")]
+    pub baud: u32,
+}
+
+fn default_bus_baud() -> u32 {
+    DEFAULT_BUS_BAUD
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Routing {
+    // Every byte a device sends reaches all of its peers
+    #[default]
+    Raw,
+    // Devices exchange avr_net frames, each delivered only to the peer with the matching address
+    AvrNet,
+}
+
+// Impairment of the connection between two devices, applied in both directions
+#[derive(Debug, Serialize)]
+pub struct LinkConfig {
+    pub between: [String; 2],
+
+    #[serde(flatten)]
+    pub impairment: Impairment,
+}
+
+// The impairment is parsed from what is left once `between` has been taken out, since serde
+// can't reject unknown keys together with `flatten`
+impl<'de> Deserialize<'de> for LinkConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut mapping = serde_yaml::Mapping::deserialize(deserializer)?;
+
+        let between = mapping.remove("between").ok_or_else(|| D::Error::missing_field("between"))?;
+        let between = serde_yaml::from_value(between).map_err(D::Error::custom)?;
+        let impairment = serde_yaml::from_value(serde_yaml::Value::Mapping(mapping)).map_err(D::Error::custom)?;
+
+        Ok(LinkConfig { between, impairment })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Device {
+    Avr(AvrDevice),
+    // A TCP listener that joins the network as a node, so the outside world can talk to devices
+    Tcp(TcpDevice),
+    // A UDP socket that joins the network as a node, e.g. standing in for a telemetry radio
+    Udp(UdpDevice),
+}
+
+impl<'de> Deserialize<'de> for Device {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut mapping = serde_yaml::Mapping::deserialize(deserializer)?;
+
+        // Devices were all AVRs before there were other types, so the type may be left out
+        let device_type = match mapping.remove("type") {
+            Some(serde_yaml::Value::String(device_type)) => device_type,
+            Some(_) => return Err(D::Error::custom("device type must be a string")),
+            None => "avr".to_string(),
+        };
+
+        let value = serde_yaml::Value::Mapping(mapping);
+
+        match device_type.as_str() {
+            "avr" => serde_yaml::from_value(value).map(Device::Avr).map_err(D::Error::custom),
+            "tcp" => serde_yaml::from_value(value).map(Device::Tcp).map_err(D::Error::custom),
+            "udp" => serde_yaml::from_value(value).map(Device::Udp).map_err(D::Error::custom),
+            _ => Err(D::Error::custom(format!("unknown device type: {}", device_type))),
+        }
+    }
+}
+
+impl Device {
+    // avr_net address of the node, used when routing frames
+    pub fn address(&self) -> Option<u16> {
+        match self {
+            Device::Avr(avr) => avr.address,
+            Device::Tcp(_) | Device::Udp(_) => None,
+        }
+    }
+
+    pub fn peers(&self) -> &[String] {
+        match self {
+            Device::Avr(avr) => &avr.peers,
+            Device::Tcp(tcp) => &tcp.peers,
+            Device::Udp(udp) => &udp.peers,
+        }
+    }
+
+    pub fn listens_to(&self) -> &[String] {
+        match self {
+            Device::Avr(avr) => &avr.listens_to,
+            Device::Tcp(_) | Device::Udp(_) => &[],
+        }
+    }
+
+    pub fn buses(&self) -> &[String] {
+        match self {
+            Device::Avr(avr) => &avr.buses,
+            Device::Tcp(_) | Device::Udp(_) => &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AvrDevice {
     pub mcu: String,
     pub firmware: String,
     pub eeprom: Option<Vec<u8>>,
 
+    // Clock of the device in Hz, or a string like "8MHz". Devices run as fast as possible without one.
+    #[serde(default, deserialize_with = "deserialize_frequency")]
+    pub frequency: Option<u32>,
+
+    // avr_net address of the device, used when routing frames
+    pub address: Option<u16>,
+
+    // Devices this one exchanges data with in both directions
     #[serde(default = "Vec::new")]
     pub peers: Vec<String>,
+
+    // Devices this one hears but doesn't transmit to, like a listen-only tap
+    #[serde(default = "Vec::new")]
+    pub listens_to: Vec<String>,
+
+    // Shared buses this device is attached to, which are created on first mention
+    #[serde(default = "Vec::new")]
+    pub buses: Vec<String>,
+
+    // Pin that must be high for the device's UART output to reach the wire,
+    // like the driver enable of an RS-485 transceiver
+    pub driver_enable: Option<PinConfig>,
+
+    // Bridge a UART to a pseudo-terminal on the host
+    pub pty: Option<PtyConfig>,
+
+    // What to do when the device crashes or its firmware finishes
+    #[serde(default)]
+    pub on_stop: StopPolicy,
+
+    // Pin of another device wired to this one's RESET, which holds it in reset while low
+    pub reset_line: Option<ResetLine>,
+}
+
+fn deserialize_frequency<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(None),
+        Value::Number(hz) => hz.as_u64()
+            .and_then(|hz| u32::try_from(hz).ok())
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid frequency: {}", hz))),
+        Value::String(text) => parse_frequency(&text).map(Some).map_err(D::Error::custom),
+        _ => Err(D::Error::custom("frequency must be a number or a string like 8MHz")),
+    }
+}
+
+fn parse_frequency(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    let lowercase = text.to_lowercase();
+
+    let (number, multiplier) = [("ghz", 1e9), ("mhz", 1e6), ("khz", 1e3), ("hz", 1.0)]
+        .iter()
+        .find_map(|(unit, multiplier)| lowercase.strip_suffix(unit).map(|number| (number, *multiplier)))
+        .unwrap_or((&lowercase, 1.0));
+
+    let hz = number.trim().parse::<f64>().map(|number| (number * multiplier).round()).ok();
+
+    match hz {
+        Some(hz) if hz >= 1.0 && hz <= u32::MAX as f64 => Ok(hz as u32),
+        _ => Err(format!("invalid frequency: {}", text)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResetLine {
+    pub node: String,
+    pub port: char,
+    pub pin: u8,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopPolicy {
+    // Leave the device stopped while the rest of the network carries on
+    #[default]
+    Ignore,
+    // Start the device again from its firmware, as if it had been power cycled
+    Restart,
+    // Shut down the whole network
+    HaltNetwork,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpDevice {
+    pub port: u16,
+
+    #[serde(default)]
+    pub mode: GatewayMode,
+
+    // avr_net address that data from clients is framed for, and that frames must be sent to in
+    // order to reach the clients. Defaults to the address of the first device behind the gateway.
+    pub address: Option<u16>,
+
+    // Give each connection its own address, counting up from `address`. The device behind the
+    // gateway has to reply to the address a request was framed for, and since those addresses
+    // aren't known to the router this only works with raw routing.
+    #[serde(default)]
+    pub per_connection: bool,
+
+    // Clients that neither send nor receive anything for this long are disconnected
+    pub idle_timeout_ms: Option<u64>,
+
+    #[serde(default = "Vec::new")]
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpDevice {
+    pub port: u16,
+
+    #[serde(default)]
+    pub mode: GatewayMode,
+
+    // Where datagrams from the network are sent. Defaults to the sender of the last datagram.
+    pub remote: Option<SocketAddr>,
+
+    // avr_net address that datagrams are framed for. Defaults to the address of the first
+    // device behind the gateway.
+    pub address: Option<u16>,
+
+    #[serde(default = "Vec::new")]
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GatewayMode {
+    // Bytes pass through untouched in both directions
+    #[default]
+    Raw,
+    // Data from clients is wrapped in avr_net frames and frames from devices are unwrapped
+    AvrNet,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PtyConfig {
+    #[serde(default = "default_pty_uart")]
+    pub uart: char,
+
+    // The pty gets a different /dev/pts/N each run, so tools can be pointed at this symlink instead
+    pub link: Option<String>,
+}
+
+fn default_pty_uart() -> char {
+    '0'
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinConfig {
+    pub port: char,
+    pub pin: u8,
+}
+
+// A problem with a config file, at a 1-based line and column when it's known
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    // The file the problem is in, which may be one that the config file includes
+    pub file: Option<PathBuf>,
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(location: Option<(usize, usize)>, message: String) -> Self {
+        Self { file: None, location, message }
+    }
+
+    fn in_file(self, file: Option<PathBuf>) -> Self {
+        Self { file, ..self }
+    }
 }
 
-pub fn load(config_file_path_str: &str) -> Result<MycochipConfig, io::Error> {
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+
+        match self.location {
+            Some((line, column)) => write!(f, "{}:{}: {}", line, column, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+pub fn load(config_file_path_str: &str, overrides: &[String]) -> Result<MycochipConfig, io::Error> {
     let config_file_path = Path::new(config_file_path_str);
 
     // Check that the config file exists
@@ -25,28 +364,900 @@ pub fn load(config_file_path_str: &str) -> Result<MycochipConfig, io::Error> {
         return Err(io::Error::new(io::ErrorKind::NotFound, "Config file not found"));
     }
 
-    // Load the config file
-    let yaml = std::fs::read_to_string(config_file_path).unwrap();
-    let mut config: MycochipConfig = serde_yaml::from_str(yaml.as_str()).unwrap();
+    check(config_file_path_str, overrides).map_err(|errors| {
+        let messages: Vec<String> = errors.iter().map(ConfigError::to_string).collect();
+        io::Error::new(io::ErrorKind::InvalidData, messages.join("\n"))
+    })
+}
+
+// Load a config file and report every problem with it, rather than just the first one. Each of
+// `overrides` sets a value, like "devices.main.frequency=8MHz".
+pub fn check(config_file_path_str: &str, overrides: &[String]) -> Result<MycochipConfig, Vec<ConfigError>> {
+    let config_file_path = Path::new(config_file_path_str);
+
+    // Problems are in the config file itself unless they're known to be in a file it includes
+    check_files(config_file_path, overrides).map_err(|errors| {
+        errors.into_iter()
+            .map(|error| match error.file {
+                Some(_) => error,
+                None => error.in_file(Some(config_file_path.to_path_buf())),
+            })
+            .collect()
+    })
+}
+
+fn check_files(config_file_path: &Path, overrides: &[String]) -> Result<MycochipConfig, Vec<ConfigError>> {
+    let config_dir = config_file_path.parent().unwrap();
+
+    let yaml = std::fs::read_to_string(config_file_path)
+        .map_err(|e| vec![ConfigError::new(None, format!("cannot read config file: {}", e))])?;
+
+    let original: Value = serde_yaml::from_str(&yaml).map_err(|e| vec![parse_error(&yaml, e)])?;
+    let (assembled, files) = assemble(config_file_path, overrides).map_err(|e| vec![e])?;
+
+    // Problems can only be pointed out exactly when the file is all there is to the config;
+    // otherwise they're pointed out at the setting they're in, in the file it came from
+    let (config, locators) = match assembled == original {
+        true => (parse(&yaml), vec![Locator::new(&yaml)]),
+        false => {
+            let locators: Vec<Locator> = files.iter()
+                .map(|(path, yaml)| Locator::new(yaml).in_file(config_dir.join(path)))
+                .collect();
+
+            (parse_value(assembled, &locators), locators)
+        }
+    };
+
+    let mut config = config?;
+
+    let mut errors = check_topology(&config, &locators);
+    errors.extend(check_avrs(&mut config, &locators, config_dir));
+
+    match errors.is_empty() {
+        true => Ok(config),
+        false => Err(errors),
+    }
+}
+
+fn parse(yaml: &str) -> Result<MycochipConfig, Vec<ConfigError>> {
+    let value: serde_yaml::Value = serde_yaml::from_str(yaml).map_err(|e| vec![parse_error(yaml, e)])?;
+
+    // Expanded configs no longer match the text, so their problems can't be located exactly
+    if templates::is_templated(&value) {
+        return parse_value(value, &[Locator::new(yaml)]);
+    }
+
+    serde_yaml::from_str(yaml).map_err(|e| every_parse_error(yaml, parse_error(yaml, e)))
+}
+
+fn parse_value(mut value: Value, locators: &[Locator]) -> Result<MycochipConfig, Vec<ConfigError>> {
+    templates::expand(&mut value).map_err(|e| vec![ConfigError::new(None, e)])?;
+
+    serde_yaml::from_value(value.clone()).map_err(|e| every_value_error(value, locators, e))
+}
+
+// Problems with a config that doesn't parse, found by parsing each device and link on its own so
+// that a problem with one doesn't hide those with the others. Each of them keeps to the lines it's
+// on, so that serde can still tell where its problems are.
+fn every_parse_error(yaml: &str, first: ConfigError) -> Vec<ConfigError> {
+    let locator = Locator::new(yaml);
+    let mut rest = locator.lines.clone();
+    let mut errors = vec![];
+
+    for (section, empty) in [("devices", "devices: {}"), ("links", "links: []")] {
+        let entries = locator.entries(&[section]);
+
+        for (index, lines) in entries.iter().enumerate() {
+            let text = "\n".repeat(lines.start) + &locator.lines[lines.clone()].join("\n");
+
+            let (name, result) = match section {
+                "devices" => {
+                    let name = locator.content(lines.start)
+                        .and_then(|(_, content)| content.split(':').next())
+                        .unwrap_or_default();
+
+                    (format!("devices.{}", name), serde_yaml::from_str::<HashMap<String, Device>>(&text).map(drop))
+                }
+                _ => (format!("links[{}]", index), serde_yaml::from_str::<Vec<LinkConfig>>(&text).map(drop)),
+            };
+
+            if let Err(e) = result {
+                let error = parse_error(&text, e);
+                errors.push(ConfigError::new(error.location, format!("{}: {}", name, error.message)));
+            }
+
+            rest[lines.clone()].fill("");
+        }
+
+        // The rest of the config is parsed with the section left empty
+        if let (false, Some((index, _))) = (entries.is_empty(), locator.find_key(&[section])) {
+            rest[index] = empty;
+        }
+    }
+
+    let rest = rest.join("\n");
+
+    if let Err(e) = serde_yaml::from_str::<MycochipConfig>(&rest) {
+        errors.push(parse_error(&rest, e));
+    }
+
+    // Whatever is in a part that couldn't be told apart is only found with the config as a whole
+    if errors.is_empty() {
+        errors.push(first);
+    }
+
+    errors.sort_by_key(|error| error.location);
+    errors
+}
+
+// Problems with an assembled config that doesn't parse, found like by `every_parse_error` but
+// pointed out at the setting they're in, since the config is no longer the text of one file
+fn every_value_error(mut value: Value, locators: &[Locator], first: serde_yaml::Error) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    if let Some(Value::Mapping(devices)) = value.get_mut("devices") {
+        let device_names: Vec<String> = devices.keys().filter_map(Value::as_str).map(str::to_string).collect();
+
+        for device_name in device_names {
+            let device = devices.remove(device_name.as_str()).unwrap();
+
+            if let Err(e) = serde_yaml::from_value::<Device>(device) {
+                let message = e.to_string();
+
+                errors.push(match unknown_field(&message) {
+                    Some(field) => error_at(locators, &["devices", &device_name, field], None, format!("devices.{}: {}", device_name, message)),
+                    None => error_at(locators, &["devices", &device_name], None, format!("devices.{}: {}", device_name, message)),
+                });
+            }
+        }
+    }
+
+    if let Some(Value::Sequence(links)) = value.get_mut("links") {
+        for (index, link) in std::mem::take(links).into_iter().enumerate() {
+            if let Err(e) = serde_yaml::from_value::<LinkConfig>(link) {
+                errors.push(error_at_link(locators, index, None, format!("links[{}]: {}", index, e)));
+            }
+        }
+    }
 
-    // FIXME: make error messages nicer when the config is wrong
+    if let Err(e) = serde_yaml::from_value::<MycochipConfig>(value) {
+        let message = e.to_string();
 
+        errors.push(match unknown_field(&message) {
+            Some(field) => error_at(locators, &[field], None, message.clone()),
+            None => ConfigError::new(None, message),
+        });
+    }
+
+    if errors.is_empty() {
+        errors.push(ConfigError::new(None, first.to_string()));
+    }
+
+    errors
+}
+
+// A config file or one that it includes, with its path relative to the directory of the former
+struct Source {
+    path: PathBuf,
+    yaml: String,
+    value: Value,
+}
+
+// Put a config together from its file and the files that includes, with variables substituted
+// and overrides applied. The text of each file is returned along with it, the included ones first.
+fn assemble(config_file_path: &Path, overrides: &[String]) -> Result<(Value, Vec<(PathBuf, String)>), ConfigError> {
     let config_dir = config_file_path.parent().unwrap();
-    for (_, device) in &mut config.devices {
-        let raw_path = Path::new(&device.firmware);
+    let file_name = config_file_path.file_name().map(Path::new).unwrap_or(config_file_path);
+
+    let mut sources = vec![];
+    read_sources(config_dir, file_name, &mut sources, &mut vec![])?;
+
+    // Variables are shared by all files, with those of the including file taking precedence
+    let mut vars = Mapping::new();
+
+    for source in &mut sources {
+        match source.value.as_mapping_mut().and_then(|config| config.remove("vars")) {
+            Some(Value::Mapping(source_vars)) => vars.extend(source_vars),
+            Some(_) => return Err(ConfigError::new(None, format!("{}: vars must be a mapping", source.path.display()))),
+            None => {}
+        }
+    }
+
+    let mut merged = Value::Mapping(Mapping::new());
+    let mut files = vec![];
+
+    for mut source in sources {
+        templates::substitute_vars(&mut source.value, &vars)
+            .map_err(|e| ConfigError::new(None, format!("{}: {}", source.path.display(), e)))?;
+
+        relocate_firmware(&mut source.value, source.path.parent().unwrap_or(Path::new("")));
+        merge(&mut merged, source.value);
+        files.push((source.path, source.yaml));
+    }
+
+    for text in overrides {
+        apply_override(&mut merged, text).map_err(|e| ConfigError::new(None, e))?;
+    }
+
+    Ok((merged, files))
+}
+
+// Read a config file and the files it includes, the latter first
+fn read_sources(config_dir: &Path, path: &Path, sources: &mut Vec<Source>, including: &mut Vec<PathBuf>) -> Result<(), ConfigError> {
+    let full_path = config_dir.join(path);
+
+    let yaml = std::fs::read_to_string(&full_path)
+        .map_err(|e| ConfigError::new(None, format!("cannot read {}: {}", path.display(), e)))?;
 
-        let firmware_path = if raw_path.is_absolute() {
-            raw_path.to_owned()
-        } else {
-            config_dir.join(raw_path)
+    let canonical_path = std::fs::canonicalize(&full_path).unwrap_or(full_path);
+
+    if including.contains(&canonical_path) {
+        return Err(ConfigError::new(None, format!("{} includes itself", path.display())));
+    }
+
+    let mut value: Value = serde_yaml::from_str(&yaml).map_err(|e| match including.is_empty() {
+        true => parse_error(&yaml, e),
+        false => parse_error(&yaml, e).in_file(Some(config_dir.join(path))),
+    })?;
+
+    let includes = match value.as_mapping_mut().and_then(|config| config.remove("include")) {
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Sequence(includes)) => includes.iter()
+            .map(|include| include.as_str().map(str::to_string))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| ConfigError::new(None, format!("{}: include must list file names", path.display())))?,
+        Some(_) => return Err(ConfigError::new(None, format!("{}: include must list file names", path.display()))),
+        None => vec![],
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    including.push(canonical_path);
+
+    for include in includes {
+        read_sources(config_dir, &dir.join(include), sources, including)?;
+    }
+
+    including.pop();
+
+    sources.push(Source { path: path.to_path_buf(), yaml, value });
+
+    Ok(())
+}
+
+// Make firmware paths in an included file relative to the directory of the top-level file
+fn relocate_firmware(value: &mut Value, dir: &Path) {
+    if dir.as_os_str().is_empty() {
+        return;
+    }
+
+    for section in ["devices", "templates"] {
+        let devices = match value.get_mut(section).and_then(Value::as_mapping_mut) {
+            Some(devices) => devices,
+            None => continue,
+        };
+
+        for device in devices.values_mut() {
+            if let Some(Value::String(firmware)) = device.get_mut("firmware") {
+                if Path::new(firmware).is_relative() {
+                    *firmware = dir.join(&firmware).to_string_lossy().into_owned();
+                }
+            }
+        }
+    }
+}
+
+// Merge `value` into `merged`, adding to mappings and lists like `devices` and `links` and
+// replacing everything else
+fn merge(merged: &mut Value, value: Value) {
+    match (merged, value) {
+        (Value::Mapping(merged), Value::Mapping(mapping)) => {
+            for (key, value) in mapping {
+                match merged.get_mut(&key) {
+                    Some(Value::Mapping(existing)) if value.is_mapping() => existing.extend(value.as_mapping().unwrap().clone()),
+                    Some(Value::Sequence(existing)) if value.is_sequence() => existing.extend(value.as_sequence().unwrap().clone()),
+                    _ => {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+        }
+        (_, Value::Null) => {}
+        (merged, value) => *merged = value,
+    }
+}
+
+// Set a value given as PATH=VALUE, e.g. "devices.main.frequency=8MHz". Everything on the path but
+// the last key must already exist, so that a typo can't add a device or section of its own.
+fn apply_override(config: &mut Value, text: &str) -> Result<(), String> {
+    let (path, raw_value) = text.split_once('=')
+        .ok_or_else(|| format!("invalid override: {}, expected PATH=VALUE", text))?;
+
+    let value = serde_yaml::from_str(raw_value).unwrap_or_else(|_| Value::String(raw_value.to_string()));
+
+    let keys: Vec<&str> = path.split('.').collect();
+    let (last_key, parent_keys) = keys.split_last().unwrap();
+    let mut target = config;
+
+    for (depth, key) in parent_keys.iter().enumerate() {
+        let parent_path = keys[..depth].join(".");
+
+        target = target.as_mapping_mut()
+            .ok_or_else(|| format!("invalid override: {}, {} isn't a mapping", text, parent_path))?
+            .get_mut(*key)
+            .ok_or_else(|| format!("invalid override: {}, there is no {}", text, keys[..=depth].join(".")))?;
+    }
+
+    target.as_mapping_mut()
+        .ok_or_else(|| format!("invalid override: {}, {} isn't a mapping", text, path))?
+        .insert(Value::String(last_key.to_string()), value);
+
+    Ok(())
+}
+
+fn parse_error(yaml: &str, e: serde_yaml::Error) -> ConfigError {
+    let location = e.location().map(|location| (location.line(), location.column()));
+
+    // The location is reported separately, like for every other problem
+    let message = e.to_string();
+    let message = match location {
+        Some((line, column)) => message
+            .strip_suffix(&format!(" at line {} column {}", line, column))
+            .unwrap_or(&message)
+            .to_string(),
+        None => message,
+    };
+
+    // Devices are parsed from a mapping of their own, so unknown keys in them are reported at
+    // the device; point at the key instead
+    let location = match (location, unknown_field(&message)) {
+        (Some((line, _)), Some(field)) => Locator::new(yaml).key_within(line - 1, field).or(location),
+        _ => location,
+    };
+
+    ConfigError::new(location, message)
+}
+
+// The key serde complains about in an "unknown field" error
+fn unknown_field(message: &str) -> Option<&str> {
+    message.split('`').nth(1).filter(|_| message.contains("unknown field"))
+}
+
+// A problem with the setting at the end of `path`, pointed out at `text` in its value when it's
+// given. Settings are looked for in the file they were taken from, i.e. the last one that has them.
+fn error_at(locators: &[Locator], path: &[&str], text: Option<&str>, message: String) -> ConfigError {
+    for locator in locators.iter().rev() {
+        let location = match text {
+            Some(text) => locator.value(path, text),
+            None => locator.key(path),
+        };
+
+        if location.is_some() {
+            return ConfigError::new(location, message).in_file(locator.file.clone());
+        }
+    }
+
+    ConfigError::new(None, message)
+}
+
+// A problem with the link at `index`, pointed out at `text` in it when it's given. Links are
+// listed by each file in turn, in the order the files are merged.
+fn error_at_link(locators: &[Locator], mut index: usize, text: Option<&str>, message: String) -> ConfigError {
+    for locator in locators {
+        let entries = locator.entries(&["links"]);
+
+        let lines = match entries.get(index) {
+            Some(lines) => lines,
+            None => {
+                index -= entries.len();
+                continue;
+            }
+        };
+
+        let location = text.and_then(|text| locator.find(lines.clone(), text))
+            .or_else(|| locator.content(lines.start).map(|(indent, _)| (lines.start + 1, indent + 1)));
+
+        return ConfigError::new(location, message).in_file(locator.file.clone());
+    }
+
+    ConfigError::new(None, message)
+}
+
+// Devices and ports referred to by other devices, which serde can't check on its own
+fn check_topology(config: &MycochipConfig, locators: &[Locator]) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    let mut device_names: Vec<&String> = config.devices.keys().collect();
+    device_names.sort();
+
+    let mut tcp_ports: HashMap<u16, &str> = HashMap::new();
+    let mut udp_ports: HashMap<u16, &str> = HashMap::new();
+
+    if let Some(websocket) = &config.websocket {
+        tcp_ports.insert(websocket.port, "websocket");
+    }
+
+    for device_name in device_names {
+        let device = &config.devices[device_name];
+
+        for (key, peer_names) in [("peers", device.peers()), ("listens_to", device.listens_to())] {
+            for peer_name in peer_names {
+                let path = ["devices", device_name, key];
+
+                if peer_name == device_name {
+                    errors.push(error_at(locators, &path, Some(peer_name), format!("{} lists itself in {}", device_name, key)));
+                } else if !config.devices.contains_key(peer_name) {
+                    errors.push(error_at(locators, &path, Some(peer_name), format!("{} refers to unknown device {}", device_name, peer_name)));
+                }
+            }
+        }
+
+        let (ports, port, protocol) = match device {
+            Device::Avr(avr) => {
+                if let Some(driver_enable) = &avr.driver_enable {
+                    if !is_pin(driver_enable.port, driver_enable.pin) {
+                        let path = ["devices", device_name, "driver_enable", "port"];
+                        errors.push(error_at(locators, &path, None, format!("{} has a driver enable on invalid pin P{}{}", device_name, driver_enable.port, driver_enable.pin)));
+                    }
+                }
+
+                if let Some(reset_line) = &avr.reset_line {
+                    if !is_pin(reset_line.port, reset_line.pin) {
+                        let path = ["devices", device_name, "reset_line", "port"];
+                        errors.push(error_at(locators, &path, None, format!("{} has a reset line on invalid pin P{}{}", device_name, reset_line.port, reset_line.pin)));
+                    }
+
+                    let path = ["devices", device_name, "reset_line", "node"];
+
+                    if &reset_line.node == device_name {
+                        errors.push(error_at(locators, &path, None, format!("{} cannot hold its own reset line", device_name)));
+                    } else if !matches!(config.devices.get(&reset_line.node), Some(Device::Avr(_))) {
+                        errors.push(error_at(locators, &path, None, format!("{} refers to unknown AVR {}", device_name, reset_line.node)));
+                    }
+                }
+
+                continue;
+            }
+            Device::Tcp(tcp) => (&mut tcp_ports, tcp.port, "TCP"),
+            Device::Udp(udp) => (&mut udp_ports, udp.port, "UDP"),
+        };
+
+        if let Some(other_name) = ports.insert(port, device_name) {
+            let path = ["devices", device_name, "port"];
+            errors.push(error_at(locators, &path, None, format!("{} port {} is already used by {}", protocol, port, other_name)));
+        }
+    }
+
+    let mut bus_names: Vec<&String> = config.buses.keys().collect();
+    bus_names.sort();
+
+    for bus_name in bus_names {
+        if config.buses[bus_name].baud == 0 {
+            errors.push(error_at(locators, &["buses", bus_name, "baud"], None, format!("baud of {} must be positive", bus_name)));
+        }
+
+        if !config.devices.values().any(|device| device.buses().contains(bus_name)) {
+            errors.push(error_at(locators, &["buses", bus_name], None, format!("no device is attached to bus {}", bus_name)));
+        }
+    }
+
+    // Links go both ways, so the same two devices in either order are the same link
+    let mut linked: HashSet<[&String; 2]> = HashSet::new();
+
+    for (index, link) in config.links.iter().enumerate() {
+        let [a, b] = &link.between;
+
+        for node_name in [a, b] {
+            if !config.devices.contains_key(node_name) {
+                errors.push(error_at_link(locators, index, Some(node_name), format!("link refers to unknown device {}", node_name)));
+            }
+        }
+
+        if a == b {
+            errors.push(error_at_link(locators, index, Some(b), format!("{} is linked to itself", a)));
+        } else if !linked.insert(if a < b { [a, b] } else { [b, a] }) {
+            errors.push(error_at_link(locators, index, None, format!("{} and {} are linked more than once", a, b)));
+        }
+    }
+
+    errors
+}
+
+// Whether a pin could exist on some AVR, i.e. its port is one of A to L and it's one of 8 pins;
+// whether the MCU at hand has it can only be told once it's running
+fn is_pin(port: char, pin: u8) -> bool {
+    ('A'..='L').contains(&port) && pin < 8
+}
+
+// MCUs and firmware of the AVRs, resolving firmware paths on the way
+fn check_avrs(config: &mut MycochipConfig, locators: &[Locator], config_dir: &Path) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    let mut device_names: Vec<String> = config.devices.keys().cloned().collect();
+    device_names.sort();
+
+    for device_name in &device_names {
+        let avr = match config.devices.get_mut(device_name) {
+            Some(Device::Avr(avr)) => avr,
+            _ => continue,
+        };
+
+        if !AvrSimulator::supports_mcu(&avr.mcu) {
+            let path = ["devices", device_name, "mcu"];
+            errors.push(error_at(locators, &path, Some(&avr.mcu), format!("{} has an unknown MCU: {}", device_name, avr.mcu)));
+        }
+
+        if let Err(err) = resolve_firmware_path(avr, config_dir) {
+            let path = ["devices", device_name, "firmware"];
+            errors.push(error_at(locators, &path, Some(&avr.firmware), format!("{}: {}", device_name, err)));
+        }
+    }
+
+    errors
+}
+
+// Finds things in block-style YAML, since serde_yaml forgets where values came from once they
+// have been parsed. Flow-style mappings aren't looked into, so nothing is found there.
+struct Locator<'a> {
+    // The file the YAML was read from, when it's not the config file
+    file: Option<PathBuf>,
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Locator<'a> {
+    fn new(yaml: &'a str) -> Self {
+        Self { file: None, lines: yaml.lines().collect() }
+    }
+
+    fn in_file(self, file: PathBuf) -> Self {
+        Self { file: Some(file), ..self }
+    }
+
+    // Location of the key at the end of `path`, e.g. ["devices", "main", "peers"]
+    fn key(&self, path: &[&str]) -> Option<(usize, usize)> {
+        self.find_key(path).map(|(index, indent)| (index + 1, indent + 1))
+    }
+
+    // Location of `text` within the value of the key at the end of `path`, falling back to the
+    // key itself
+    fn value(&self, path: &[&str], text: &str) -> Option<(usize, usize)> {
+        let (index, indent) = self.find_key(path)?;
+        let end = self.block_end(index, indent);
+
+        self.find(index..end, text).or(Some((index + 1, indent + 1)))
+    }
+
+    // Location of `text` within `lines`, past the key on the first of them, which may contain
+    // the same text
+    fn find(&self, lines: Range<usize>, text: &str) -> Option<(usize, usize)> {
+        let start = lines.start;
+
+        self.lines[lines].iter().enumerate().find_map(|(i, line)| {
+            let skip = match i {
+                0 => line.find(':').map(|i| i + 1).unwrap_or(0),
+                _ => 0,
+            };
+
+            line[skip..].find(text).map(|column| (start + i + 1, skip + column + 1))
+        })
+    }
+
+    // Lines of each entry of the block-style mapping or list at the end of `path`
+    fn entries(&self, path: &[&str]) -> Vec<Range<usize>> {
+        let (index, indent) = match self.find_key(path) {
+            Some(found) => found,
+            None => return vec![],
         };
 
-        if !firmware_path.exists() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Firmware file not found: {}", firmware_path.display())));
+        let end = self.block_end(index, indent);
+
+        // Entries all have the same indentation as the first one, and anything between them
+        // that's indented further belongs to the one before
+        let mut child_indent = None;
+        let starts: Vec<usize> = (index + 1..end)
+            .filter(|&i| matches!(self.content(i), Some((line_indent, _)) if *child_indent.get_or_insert(line_indent) == line_indent))
+            .collect();
+
+        starts.iter().enumerate()
+            .map(|(n, &start)| start..starts.get(n + 1).copied().unwrap_or(end))
+            .collect()
+    }
+
+    // Location of the first `key` at any depth in the block that starts at line `index`
+    fn key_within(&self, index: usize, key: &str) -> Option<(usize, usize)> {
+        let (indent, _) = self.content(index)?;
+        let end = self.block_end(index, indent);
+
+        (index + 1..end).find_map(|i| {
+            let (indent, content) = self.content(i)?;
+            let content = content.trim_start_matches("- ");
+
+            match content.strip_prefix(key)?.trim_start().starts_with(':') {
+                true => Some((i + 1, indent + 1 + self.lines[i].trim_start().len() - content.len())),
+                false => None,
+            }
+        })
+    }
+
+    // Index and indentation of the line with the key at the end of `path`
+    fn find_key(&self, path: &[&str]) -> Option<(usize, usize)> {
+        let mut start = 0;
+        let mut end = self.lines.len();
+        let mut found = None;
+
+        for key in path {
+            // Keys of a mapping all have the same indentation as its first one
+            let mut child_indent = None;
+            found = None;
+
+            for index in start..end {
+                let (indent, content) = match self.content(index) {
+                    Some(content) => content,
+                    None => continue,
+                };
+
+                if *child_indent.get_or_insert(indent) != indent {
+                    continue;
+                }
+
+                let is_key = content.strip_prefix(key)
+                    .map(|rest| rest.trim_start().starts_with(':'))
+                    .unwrap_or(false);
+
+                if is_key {
+                    found = Some((index, indent));
+                    break;
+                }
+            }
+
+            let (index, indent) = found?;
+            start = index + 1;
+            end = self.block_end(index, indent);
+        }
+
+        found
+    }
+
+    // Index of the first line after the block that starts at `index`
+    fn block_end(&self, index: usize, indent: usize) -> usize {
+        (index + 1..self.lines.len())
+            .find(|&i| matches!(self.content(i), Some((line_indent, _)) if line_indent <= indent))
+            .unwrap_or(self.lines.len())
+    }
+
+    // Indentation and content of a line, unless it's blank or a comment
+    fn content(&self, index: usize) -> Option<(usize, &'a str)> {
+        let line = self.lines[index];
+        let content = line.trim_start();
+
+        match content.is_empty() || content.starts_with('#') {
+            true => None,
+            false => Some((line.len() - content.len(), content)),
+        }
+    }
+}
+
+// Load a single device description, e.g. to add it to a running network
+pub fn load_device(device_file_path_str: &str) -> Result<Device, io::Error> {
+    let device_file_path = Path::new(device_file_path_str);
+
+    if !device_file_path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Device file not found"));
+    }
+
+    let yaml = std::fs::read_to_string(device_file_path)?;
+    let mut device: Device = serde_yaml::from_str(yaml.as_str())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    if let Device::Avr(avr) = &mut device {
+        resolve_firmware_path(avr, device_file_path.parent().unwrap())?;
+
+        // The device is handed to a running network which may have a different working directory
+        avr.firmware = std::fs::canonicalize(&avr.firmware)?.to_str().unwrap().to_owned();
+    }
+
+    Ok(device)
+}
+
+// Make the firmware path absolute, relative to the directory of the file that declared it
+fn resolve_firmware_path(device: &mut AvrDevice, config_dir: &Path) -> Result<(), io::Error> {
+    let raw_path = Path::new(&device.firmware);
+
+    let firmware_path = if raw_path.is_absolute() {
+        raw_path.to_owned()
+    } else {
+        config_dir.join(raw_path)
+    };
+
+    if !firmware_path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Firmware file not found: {}", firmware_path.display())));
+    }
+
+    device.firmware = firmware_path.to_str().unwrap().to_owned();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{apply_override, check_topology, merge, parse, parse_frequency, parse_value, Device, GatewayMode, Locator, StopPolicy};
+
+    #[test]
+    fn device_type_defaults_to_avr() {
+        let device: Device = serde_yaml::from_str("mcu: atmega328p\nfirmware: main.elf\n").unwrap();
+        assert!(matches!(device, Device::Avr(avr) if avr.mcu == "atmega328p"));
+    }
+
+    #[test]
+    fn stop_policy_is_read() {
+        let device: Device = serde_yaml::from_str("mcu: atmega328p\nfirmware: main.elf\non_stop: halt_network\n").unwrap();
+        assert!(matches!(device, Device::Avr(avr) if avr.on_stop == StopPolicy::HaltNetwork));
+
+        let device: Device = serde_yaml::from_str("mcu: atmega328p\nfirmware: main.elf\n").unwrap();
+        assert!(matches!(device, Device::Avr(avr) if avr.on_stop == StopPolicy::Ignore));
+    }
+
+    #[test]
+    fn tcp_device_round_trips() {
+        let device: Device = serde_yaml::from_str("type: tcp\nport: 7001\nmode: avr_net\n").unwrap();
+        let device: Device = serde_yaml::from_str(&serde_yaml::to_string(&device).unwrap()).unwrap();
+
+        match device {
+            Device::Tcp(tcp) => {
+                assert_eq!(tcp.port, 7001);
+                assert_eq!(tcp.mode, GatewayMode::AvrNet);
+            }
+            _ => panic!("Expected a TCP device"),
         }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected_with_their_location() {
+        let errors = parse("devices: {}\nsede: 1\n").unwrap_err();
+        assert_eq!(errors[0].location, Some((2, 1)));
+        assert!(errors[0].message.contains("sede"));
+
+        let errors = parse("devices:\n  main:\n    mcu: atmega328p\n    firmware: main.elf\n    peer: [cam]\n").unwrap_err();
+        assert_eq!(errors[0].location, Some((5, 5)));
+        assert!(errors[0].message.contains("peer"));
+
+        let errors = parse("devices: {}\nlinks:\n  - between: [a, b]\n    latency: 10\n").unwrap_err();
+        assert_eq!(errors[0].location, Some((4, 5)));
+        assert!(errors[0].message.contains("latency"));
+    }
+
+    #[test]
+    fn every_parse_error_is_reported() {
+        let yaml = "\
+devices:
+  main:
+    mcu: atmega328p
+    firmware: main.elf
+    peer: [cam]
+  cam:
+    firmware: cam.elf
+  radio:
+    mcu: atmega328p
+    firmware: radio.elf
+links:
+  - between: [main, cam]
+  - between: [main, radio]
+    latency: 10
+sede: 1
+";
+        let errors = parse(yaml).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
 
-        device.firmware = firmware_path.to_str().unwrap().to_owned();
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].starts_with("5:5: devices.main: unknown field `peer`"));
+        assert!(messages[1].starts_with("6:3: devices.cam: missing field `mcu`"));
+        assert!(messages[2].starts_with("14:5: links[1]: unknown field `latency`"));
+        assert!(messages[3].starts_with("15:1: unknown field `sede`"));
     }
 
-    return Ok(config);
+    #[test]
+    fn every_topology_problem_is_reported() {
+        let yaml = "\
+devices:
+  main:
+    mcu: atmega328p
+    firmware: main.elf
+    peers:
+      - main
+      - radio
+  cam:
+    mcu: atmega328p
+    firmware: cam.elf
+    reset_line:
+      node: main
+      port: b
+      pin: 8
+    driver_enable:
+      port: D
+      pin: 9
+  http:
+    type: tcp
+    port: 7001
+  api:
+    type: tcp
+    port: 7001
+buses:
+  rs485:
+    baud: 0
+links:
+  - between: [main, cam]
+  - between: [cam, main]
+  - between: [cam, cam]
+";
+        let config = parse(yaml).unwrap();
+        let errors = check_topology(&config, &[Locator::new(yaml)]);
+
+        let mut messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        messages.sort();
+
+        assert_eq!(messages, [
+            "13:7: cam has a reset line on invalid pin Pb8",
+            "16:7: cam has a driver enable on invalid pin PD9",
+            "20:5: TCP port 7001 is already used by api",
+            "25:3: no device is attached to bus rs485",
+            "26:5: baud of rs485 must be positive",
+            "29:3: cam and main are linked more than once",
+            "30:15: cam is linked to itself",
+            "6:9: main lists itself in peers",
+            "7:9: main refers to unknown device radio",
+        ]);
+    }
+
+    #[test]
+    fn problems_are_pointed_out_in_the_file_they_are_in() {
+        let included = "devices:\n  cam:\n    mcu: atmega328p\n    firmware: cam.elf\n    peers: [radio]\n";
+        let top = "include: cam.yaml\ndevices:\n  main:\n    mcu: atmega328p\n    firmware: main.elf\n    peers: [main]\n";
+
+        let mut config: serde_yaml::Value = serde_yaml::from_str(included).unwrap();
+        merge(&mut config, serde_yaml::from_str(top).unwrap());
+        config.as_mapping_mut().unwrap().remove("include");
+
+        let locators = [Locator::new(included).in_file("configs/cam.yaml".into()), Locator::new(top)];
+        let config = parse_value(config, &locators).unwrap();
+
+        let mut messages: Vec<String> = check_topology(&config, &locators).iter().map(|e| e.to_string()).collect();
+        messages.sort();
+
+        assert_eq!(messages, [
+            "6:13: main lists itself in peers",
+            "configs/cam.yaml:5:13: cam refers to unknown device radio",
+        ]);
+    }
+
+    #[test]
+    fn frequencies_take_units() {
+        assert_eq!(parse_frequency("16000000"), Ok(16_000_000));
+        assert_eq!(parse_frequency("8MHz"), Ok(8_000_000));
+        assert_eq!(parse_frequency("7.3728 MHz"), Ok(7_372_800));
+        assert_eq!(parse_frequency("32kHz"), Ok(32_000));
+        assert!(parse_frequency("fast").is_err());
+        assert!(parse_frequency("5GHz").is_err());
+    }
+
+    #[test]
+    fn included_and_overridden_settings_are_merged() {
+        let mut config: serde_yaml::Value = serde_yaml::from_str("devices: {main: {mcu: atmega328p}}\nlinks: [{between: [a, b]}]\n").unwrap();
+        merge(&mut config, serde_yaml::from_str("devices: {cam: {mcu: atmega328p}}\nlinks: [{between: [b, c]}]\nseed: 3\n").unwrap());
+        apply_override(&mut config, "devices.main.frequency=8MHz").unwrap();
+
+        assert_eq!(config["devices"].as_mapping().unwrap().len(), 2);
+        assert_eq!(config["links"].as_sequence().unwrap().len(), 2);
+        assert_eq!(config["seed"], serde_yaml::Value::from(3));
+        assert_eq!(config["devices"]["main"]["frequency"], serde_yaml::Value::from("8MHz"));
+        assert!(apply_override(&mut config, "seed").is_err());
+
+        let error = apply_override(&mut config, "devices.sensr.frequency=8MHz").unwrap_err();
+        assert!(error.contains("there is no devices.sensr"));
+        assert!(!config["devices"].as_mapping().unwrap().contains_key("sensr"));
+    }
+
+    #[test]
+    fn unknown_device_type_is_rejected() {
+        assert!(serde_yaml::from_str::<Device>("type: can\n").is_err());
+    }
 }
@@ -0,0 +1,101 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::avr_simulator::state::AvrState;
+use crate::comms::{self, EVENT_SCHEMA_VERSION};
+use crate::comms::event::{event::Kind, BusData, DeviceState, Event, GatewayConnection, LogLine, PinChange, Shutdown, StateChange};
+
+// Publishes what happens in the network as `Event` messages, each after a topic frame that
+// subscribers can filter on
+pub struct EventPublisher {
+    socket: zmq::Socket,
+}
+
+impl EventPublisher {
+    pub fn new(socket: zmq::Socket) -> Self {
+        Self { socket }
+    }
+
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    fn publish(&self, topic: &str, node: &str, sim_time: Duration, kind: Kind) -> Result<(), zmq::Error> {
+        let wall_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let event = Event {
+            schema_version: EVENT_SCHEMA_VERSION,
+            node: node.to_string(),
+            sim_time_us: sim_time.as_micros() as u64,
+            wall_time_us: wall_time.as_micros() as u64,
+            kind: Some(kind),
+        };
+
+        self.socket.send(topic.as_bytes(), zmq::SNDMORE)?;
+        self.socket.send(comms::serialize_event(&event), 0)
+    }
+
+    pub fn bus_data(&self, node: &str, sim_time: Duration, data: &[u8]) -> Result<(), zmq::Error> {
+        let topic = format!("{}/bus", node);
+        self.publish(&topic, node, sim_time, Kind::BusData(BusData { data: data.to_vec() }))
+    }
+
+    pub fn pin_change(&self, node: &str, sim_time: Duration, port: char, pin_index: u8, state: bool) -> Result<(), zmq::Error> {
+        let topic = format!("{}/pin/{}/{}", node, port, pin_index);
+        self.publish(&topic, node, sim_time, Kind::PinChange(PinChange {
+            port: port.to_string(),
+            pin_index: pin_index as u32,
+            state,
+        }))
+    }
+
+    pub fn state_change(
+        &self,
+        node: &str,
+        sim_time: Duration,
+        previous_state: AvrState,
+        state: AvrState,
+        pc: u32,
+        cycle: u64,
+    ) -> Result<(), zmq::Error> {
+        let topic = format!("{}/state", node);
+        self.publish(&topic, node, sim_time, Kind::StateChange(StateChange {
+            previous_state: device_state(previous_state).into(),
+            state: device_state(state).into(),
+            pc,
+            cycle,
+        }))
+    }
+
+    pub fn gateway_connection(&self, node: &str, sim_time: Duration, client_id: u64, connected: bool) -> Result<(), zmq::Error> {
+        let topic = format!("{}/connection", node);
+        self.publish(&topic, node, sim_time, Kind::GatewayConnection(GatewayConnection { client_id, connected }))
+    }
+
+    // Print a line and publish it, about a node or about the network as a whole if `node` is empty
+    pub fn log(&self, node: &str, sim_time: Duration, text: &str) -> Result<(), zmq::Error> {
+        println!("{}", text);
+
+        let topic = match node {
+            "" => "mycochip/log".to_string(),
+            node => format!("{}/log", node),
+        };
+
+        self.publish(&topic, node, sim_time, Kind::LogLine(LogLine { text: text.to_string() }))
+    }
+
+    pub fn shutdown(&self, sim_time: Duration, crashed: Vec<String>) -> Result<(), zmq::Error> {
+        self.publish("mycochip/shutdown", "", sim_time, Kind::Shutdown(Shutdown { crashed }))
+    }
+}
+
+fn device_state(state: AvrState) -> DeviceState {
+    match state {
+        AvrState::Limbo => DeviceState::Limbo,
+        AvrState::Stopped => DeviceState::Stopped,
+        AvrState::Running => DeviceState::Running,
+        AvrState::Sleeping => DeviceState::Sleeping,
+        AvrState::Step => DeviceState::Step,
+        AvrState::StepDone => DeviceState::StepDone,
+        AvrState::Done => DeviceState::Done,
+        AvrState::Crashed => DeviceState::Crashed,
+    }
+}
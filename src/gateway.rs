@@ -0,0 +1,433 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crate::avr_net::{self, AvrNetMessage, Framing};
+use crate::config::GatewayMode;
+use crate::network::NetworkReceive;
+use crate::server_node::{ClientId, ServerNode};
+
+// The examples reply to address 0, i.e. to whoever is outside the network, so frames sent here
+// reach every client of a gateway
+pub const GATEWAY_ADDRESS: u16 = 0;
+// Where data from clients goes if no device behind the gateway has an address
+pub const DEFAULT_CLIENT_ADDRESS: u16 = 1;
+
+// Largest payload that fits in a UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// A node of the network that bridges it to the outside world
+pub trait Gateway {
+    // Take what has come in from outside, ready to go out on the network
+    fn collect(&self) -> Vec<u8>;
+
+    // Set the avr_net address that data from outside is framed for
+    fn bind_address(&self, address: u16);
+
+    // Clients that have connected (true) or disconnected (false) since the last call
+    fn connection_changes(&self) -> Vec<(ClientId, bool)> {
+        vec![]
+    }
+
+    // Close the connections to the outside world, after sending what is queued for them
+    fn shutdown(self: Box<Self>);
+}
+
+// The avr_net addresses of the clients of a TCP gateway. Data from a client is framed for its
+// address and frames sent to that address are delivered only to that client.
+pub struct ClientAddresses {
+    address: u16,
+    per_connection: bool,
+    // client id -> address, when each connection has its own
+    assigned: HashMap<ClientId, u16>,
+}
+
+impl ClientAddresses {
+    pub fn new(address: u16, per_connection: bool) -> Self {
+        Self {
+            address,
+            per_connection,
+            assigned: HashMap::new(),
+        }
+    }
+
+    // Hands out the lowest free address to a client seen for the first time
+    pub fn address_of(&mut self, client_id: ClientId) -> Option<u16> {
+        if !self.per_connection {
+            return Some(self.address);
+        }
+
+        if let Some(address) = self.assigned.get(&client_id) {
+            return Some(*address);
+        }
+
+        let used: HashSet<u16> = self.assigned.values().copied().collect();
+        let address = (self.address..avr_net::BROADCAST_ADDRESS).find(|a| !used.contains(a))?;
+        self.assigned.insert(client_id, address);
+
+        Some(address)
+    }
+
+    pub fn clients_at(&self, address: u16, client_ids: &[ClientId]) -> Vec<ClientId> {
+        client_ids.iter()
+            .copied()
+            .filter(|id| match self.per_connection {
+                true => self.assigned.get(id) == Some(&address),
+                false => self.address == address,
+            })
+            .collect()
+    }
+
+    // Free the addresses of clients that have disconnected
+    pub fn retain_connected(&mut self, client_ids: &[ClientId]) {
+        self.assigned.retain(|id, _| client_ids.contains(id));
+    }
+}
+
+pub struct TcpReceiver {
+    mode: GatewayMode,
+    // Parses messages from the chips in the network
+    avr_net_node: avr_net::AvrNetState,
+    tcp_server: Arc<ServerNode>,
+    client_addresses: Rc<RefCell<ClientAddresses>>,
+}
+
+impl NetworkReceive<'_> for TcpReceiver {
+    fn receive(&mut self, b: u8) {
+        if self.mode == GatewayMode::Raw {
+            for id in self.tcp_server.connected_client_ids() {
+                self.tcp_server.send_data(id, &[b]);
+            }
+
+            return;
+        }
+
+        let message = match self.avr_net_node.rx(b) {
+            Some(message) => message,
+            None => return,
+        };
+
+        let connected = self.tcp_server.connected_client_ids();
+        let recipients = match message.address {
+            GATEWAY_ADDRESS => connected,
+            address => self.client_addresses.borrow().clients_at(address, &connected),
+        };
+
+        for id in recipients {
+            self.tcp_server.send_data(id, &message.data);
+        }
+    }
+}
+
+// A TCP listener that is a node of the network. In raw mode bytes pass straight through between
+// the clients and the peers of the gateway, otherwise they are carried in avr_net frames.
+pub struct TcpGateway {
+    server: Arc<ServerNode>,
+    server_thread: thread::JoinHandle<()>,
+    mode: GatewayMode,
+    framing: Framing,
+    per_connection: bool,
+    client_addresses: Rc<RefCell<ClientAddresses>>,
+    // Clients that were connected when connection_changes was last called
+    known_clients: RefCell<HashSet<ClientId>>,
+}
+
+impl TcpGateway {
+    pub fn start(
+        port: u16,
+        mode: GatewayMode,
+        framing: Framing,
+        per_connection: bool,
+        idle_timeout: Option<Duration>,
+    ) -> Result<Self, io::Error> {
+        let server = ServerNode::new(&format!("0.0.0.0:{}", port))?.with_idle_timeout(idle_timeout);
+        let server = Arc::new(server);
+
+        let server_thread = {
+            let server = server.clone();
+            thread::spawn(move || server.start())
+        };
+
+        Ok(Self {
+            server,
+            server_thread,
+            mode,
+            framing,
+            per_connection,
+            client_addresses: Rc::new(RefCell::new(ClientAddresses::new(DEFAULT_CLIENT_ADDRESS, per_connection))),
+            known_clients: RefCell::new(HashSet::new()),
+        })
+    }
+
+    // The node that takes data from the network to the clients
+    pub fn receiver(&self) -> TcpReceiver {
+        TcpReceiver {
+            mode: self.mode,
+            avr_net_node: avr_net::AvrNetState::any_address().with_framing(self.framing),
+            tcp_server: self.server.clone(),
+            client_addresses: self.client_addresses.clone(),
+        }
+    }
+}
+
+impl Gateway for TcpGateway {
+    fn collect(&self) -> Vec<u8> {
+        let client_ids = self.server.connected_client_ids();
+        self.client_addresses.borrow_mut().retain_connected(&client_ids);
+
+        let mut data = Vec::new();
+
+        for id in client_ids {
+            let tcp_data = match self.server.read_data(id) {
+                Some(tcp_data) => tcp_data,
+                None => continue,
+            };
+
+            if self.mode == GatewayMode::Raw {
+                data.extend(tcp_data);
+                continue;
+            }
+
+            let client_address = match self.client_addresses.borrow_mut().address_of(id) {
+                Some(address) => address,
+                None => {
+                    println!("No avr_net address left for client {}", id);
+                    continue;
+                }
+            };
+
+            // Split up the data so that each frame fits in the receive buffer
+            for chunk in tcp_data.chunks(avr_net::MAX_DATA_LENGTH) {
+                let avr_net_message = AvrNetMessage {
+                    address: client_address,
+                    data: chunk.to_vec(),
+                }.encode(self.framing).unwrap();
+
+                data.extend(avr_net_message);
+            }
+        }
+
+        data
+    }
+
+    fn connection_changes(&self) -> Vec<(ClientId, bool)> {
+        let connected: HashSet<ClientId> = self.server.connected_client_ids().into_iter().collect();
+        let mut known_clients = self.known_clients.borrow_mut();
+
+        let mut changes: Vec<(ClientId, bool)> = known_clients.difference(&connected).map(|id| (*id, false)).collect();
+        changes.extend(connected.difference(&known_clients).map(|id| (*id, true)));
+        changes.sort();
+
+        *known_clients = connected;
+
+        changes
+    }
+
+    fn bind_address(&self, address: u16) {
+        *self.client_addresses.borrow_mut() = ClientAddresses::new(address, self.per_connection);
+    }
+
+    fn shutdown(self: Box<Self>) {
+        self.server.shutdown();
+
+        if self.server_thread.join().is_err() {
+            println!("TCP server thread panicked");
+        }
+    }
+}
+
+pub struct UdpReceiver {
+    mode: GatewayMode,
+    avr_net_node: avr_net::AvrNetState,
+    socket: Rc<UdpSocket>,
+    destination: Rc<Destination>,
+    // Raw bytes are held back so that they go out together instead of a datagram per byte
+    pending: Rc<RefCell<Vec<u8>>>,
+}
+
+impl NetworkReceive<'_> for UdpReceiver {
+    fn receive(&mut self, b: u8) {
+        if self.mode == GatewayMode::Raw {
+            self.pending.borrow_mut().push(b);
+            return;
+        }
+
+        let message = match self.avr_net_node.rx(b) {
+            Some(message) => message,
+            None => return,
+        };
+
+        if message.address != GATEWAY_ADDRESS && message.address != self.destination.address.get() {
+            return;
+        }
+
+        self.destination.send(&self.socket, &message.data);
+    }
+}
+
+// Where datagrams from the network go, and the address of the peer on the other side
+struct Destination {
+    remote: Option<SocketAddr>,
+    last_seen: Cell<Option<SocketAddr>>,
+    address: Cell<u16>,
+}
+
+impl Destination {
+    fn send(&self, socket: &UdpSocket, data: &[u8]) {
+        let remote = match self.remote.or(self.last_seen.get()) {
+            Some(remote) => remote,
+            // Nobody to send to until a datagram arrives
+            None => return,
+        };
+
+        if let Err(err) = socket.send_to(data, remote) {
+            println!("Cannot send datagram to {}: {}", remote, err);
+        }
+    }
+}
+
+// A UDP socket that is a node of the network, like a telemetry radio. In avr_net mode each
+// datagram is carried in exactly one frame, in raw mode the bytes pass straight through.
+// Datagrams go to the configured remote, or else to whoever sent the last datagram.
+pub struct UdpGateway {
+    socket: Rc<UdpSocket>,
+    mode: GatewayMode,
+    framing: Framing,
+    destination: Rc<Destination>,
+    pending: Rc<RefCell<Vec<u8>>>,
+}
+
+impl UdpGateway {
+    pub fn start(port: u16, mode: GatewayMode, framing: Framing, remote: Option<SocketAddr>) -> Result<Self, io::Error> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: Rc::new(socket),
+            mode,
+            framing,
+            destination: Rc::new(Destination {
+                remote,
+                last_seen: Cell::new(None),
+                address: Cell::new(DEFAULT_CLIENT_ADDRESS),
+            }),
+            pending: Rc::new(RefCell::new(Vec::new())),
+        })
+    }
+
+    // The node that takes data from the network to the remote
+    pub fn receiver(&self) -> UdpReceiver {
+        UdpReceiver {
+            mode: self.mode,
+            avr_net_node: avr_net::AvrNetState::any_address().with_framing(self.framing),
+            socket: self.socket.clone(),
+            destination: self.destination.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl Gateway for UdpGateway {
+    fn collect(&self) -> Vec<u8> {
+        // Raw bytes that arrived since the last call go out as one datagram
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        for chunk in pending.chunks(MAX_DATAGRAM_SIZE) {
+            self.destination.send(&self.socket, chunk);
+        }
+
+        let mut data = Vec::new();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, sender) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("Cannot receive datagram: {}", err);
+                    break;
+                }
+            };
+
+            self.destination.last_seen.set(Some(sender));
+
+            if self.mode == GatewayMode::Raw {
+                data.extend(&buf[..len]);
+                continue;
+            }
+
+            if len > avr_net::MAX_DATA_LENGTH {
+                println!("Dropped a datagram of {} bytes from {}, which doesn't fit in a frame", len, sender);
+                continue;
+            }
+
+            let avr_net_message = AvrNetMessage {
+                address: self.destination.address.get(),
+                data: buf[..len].to_vec(),
+            }.encode(self.framing).unwrap();
+
+            data.extend(avr_net_message);
+        }
+
+        data
+    }
+
+    fn bind_address(&self, address: u16) {
+        self.destination.address.set(address);
+    }
+
+    fn shutdown(self: Box<Self>) {
+        // Raw bytes still waiting to be sent
+        self.collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+    use crate::avr_net::{AvrNetMessage, Framing};
+    use crate::config::GatewayMode;
+    use crate::gateway::{Gateway, UdpGateway};
+    use crate::network::NetworkReceive;
+
+    fn collect_some(gateway: &UdpGateway) -> Vec<u8> {
+        for _ in 0..100 {
+            let data = gateway.collect();
+            if !data.is_empty() {
+                return data;
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        vec![]
+    }
+
+    #[test]
+    fn udp_datagrams_are_framed() {
+        let gateway = UdpGateway::start(0, GatewayMode::AvrNet, Framing::Legacy, None).unwrap();
+        gateway.bind_address(3);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let gateway_port = gateway.socket.local_addr().unwrap().port();
+        client.send_to(b"ping", ("127.0.0.1", gateway_port)).unwrap();
+
+        let expected = AvrNetMessage { address: 3, data: b"ping".to_vec() }.encode(Framing::Legacy).unwrap();
+        assert_eq!(collect_some(&gateway), expected);
+
+        // Replies go back to whoever sent the last datagram
+        let mut receiver = gateway.receiver();
+        for b in (AvrNetMessage { address: 0, data: b"pong".to_vec() }).encode(Framing::Legacy).unwrap() {
+            receiver.receive(b);
+        }
+
+        let mut buf = [0; 16];
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+    }
+}
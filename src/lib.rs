@@ -1,6 +1,14 @@
-mod config;
-mod comms;
+pub mod config;
+mod templates;
+pub mod comms;
 pub mod avr_simulator;
-mod network;
+pub mod network;
+pub mod link;
 mod server_node;
-mod avr_net;
+pub mod gateway;
+pub mod pty_bridge;
+pub mod scenario;
+pub mod simulation;
+pub mod websocket;
+pub mod events;
+pub mod avr_net;
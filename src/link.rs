@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+// UART framing is 8N1: a start bit, 8 data bits and a stop bit
+const BITS_PER_BYTE: u64 = 10;
+
+// Ways in which a link deviates from a perfect wire. The default is a perfect wire.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Impairment {
+    // Probability that a byte is lost entirely
+    pub drop_probability: f64,
+
+    // Probability that each bit of a byte is inverted
+    pub bit_flip_rate: f64,
+
+    // Delay added to every byte, in microseconds of simulated time
+    pub latency_us: u64,
+
+    // Upper bound of a random delay added on top of `latency_us`
+    pub jitter_us: u64,
+
+    // Maximum rate of the link in bits per second, or 0 for unlimited
+    pub bandwidth_bps: u64,
+
+    // Windows of simulated time during which nothing gets through the link
+    pub partitions: Vec<Partition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub start_ms: u64,
+    pub duration_ms: u64,
+}
+
+impl Impairment {
+    pub fn is_perfect(&self) -> bool {
+        *self == Impairment::default()
+    }
+
+    fn is_partitioned(&self, now: Duration) -> bool {
+        self.partitions.iter().any(|partition| {
+            let start = Duration::from_millis(partition.start_ms);
+            let end = start + Duration::from_millis(partition.duration_ms);
+
+            now >= start && now < end
+        })
+    }
+}
+
+// One direction of a connection between two nodes, with the bytes currently in flight.
+pub(crate) struct Link {
+    impairment: Impairment,
+    rng: Rng,
+    // Bytes on the wire and the simulated time at which each one arrives
+    in_flight: VecDeque<(Duration, u8)>,
+    // When the sender is done transmitting the bytes it has already sent
+    busy_until: Duration,
+}
+
+impl Link {
+    pub(crate) fn new(impairment: Impairment, seed: u64) -> Self {
+        Self {
+            impairment,
+            rng: Rng::new(seed),
+            in_flight: VecDeque::new(),
+            busy_until: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn set_impairment(&mut self, impairment: Impairment) {
+        self.impairment = impairment;
+    }
+
+    pub(crate) fn send(&mut self, now: Duration, b: u8) {
+        let impairment = &self.impairment;
+
+        // Even a byte that ends up lost occupies the wire while it is sent
+        let mut departure = now.max(self.busy_until);
+        if let Some(nanos) = (BITS_PER_BYTE * 1_000_000_000).checked_div(impairment.bandwidth_bps) {
+            departure += Duration::from_nanos(nanos);
+            self.busy_until = departure;
+        }
+
+        if impairment.is_partitioned(now) {
+            return;
+        }
+
+        if impairment.drop_probability > 0.0 && self.rng.next_f64() < impairment.drop_probability {
+            return;
+        }
+
+        let mut b = b;
+        if impairment.bit_flip_rate > 0.0 {
+            for bit_idx in 0..8 {
+                if self.rng.next_f64() < impairment.bit_flip_rate {
+                    b ^= 1 << bit_idx;
+                }
+            }
+        }
+
+        let jitter = self.rng.below(impairment.jitter_us + 1);
+        let mut arrival = departure + Duration::from_micros(impairment.latency_us + jitter);
+
+        // Jitter varies the delay but a wire doesn't reorder bytes
+        if let Some((last_arrival, _)) = self.in_flight.back() {
+            arrival = arrival.max(*last_arrival);
+        }
+
+        self.in_flight.push_back((arrival, b));
+    }
+
+    // Take the next byte that has arrived by the given time
+    pub(crate) fn receive(&mut self, now: Duration) -> Option<u8> {
+        match self.in_flight.front() {
+            Some((arrival, _)) if *arrival <= now => self.in_flight.pop_front().map(|(_, b)| b),
+            _ => None,
+        }
+    }
+}
+
+// Derive a seed for a link so that each one has its own reproducible sequence
+pub(crate) fn link_seed(seed: u64, from: &str, to: &str) -> u64 {
+    // FNV-1a, which unlike the std hasher is guaranteed to be stable
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in from.bytes().chain([0]).chain(to.bytes()) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    seed ^ hash
+}
+
+// SplitMix64, kept in-tree so that runs stay reproducible regardless of dependency versions
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniformly distributed in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniformly distributed in [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::link::{Impairment, Link, Partition};
+
+    fn send_all(link: &mut Link, now: Duration, data: &[u8]) {
+        for b in data {
+            link.send(now, *b);
+        }
+    }
+
+    fn receive_all(link: &mut Link, now: Duration) -> Vec<u8> {
+        std::iter::from_fn(|| link.receive(now)).collect()
+    }
+
+    #[test]
+    fn latency_delays_delivery() {
+        let mut link = Link::new(Impairment { latency_us: 100, ..Default::default() }, 0);
+        send_all(&mut link, Duration::ZERO, &[1, 2]);
+
+        assert!(receive_all(&mut link, Duration::from_micros(99)).is_empty());
+        assert_eq!(receive_all(&mut link, Duration::from_micros(100)), vec![1, 2]);
+    }
+
+    #[test]
+    fn bandwidth_spaces_out_bytes() {
+        // 10 bits per byte at 10kbps is one byte per millisecond
+        let mut link = Link::new(Impairment { bandwidth_bps: 10_000, ..Default::default() }, 0);
+        send_all(&mut link, Duration::ZERO, &[1, 2, 3]);
+
+        assert_eq!(receive_all(&mut link, Duration::from_millis(2)), vec![1, 2]);
+        assert_eq!(receive_all(&mut link, Duration::from_millis(3)), vec![3]);
+    }
+
+    #[test]
+    fn partition_drops_bytes() {
+        let partition = Partition { start_ms: 1, duration_ms: 1 };
+        let mut link = Link::new(Impairment { partitions: vec![partition], ..Default::default() }, 0);
+
+        send_all(&mut link, Duration::ZERO, &[1]);
+        send_all(&mut link, Duration::from_millis(1), &[2]);
+        send_all(&mut link, Duration::from_millis(2), &[3]);
+
+        assert_eq!(receive_all(&mut link, Duration::from_millis(2)), vec![1, 3]);
+    }
+
+    #[test]
+    fn same_seed_same_corruption() {
+        let impairment = Impairment { drop_probability: 0.3, bit_flip_rate: 0.1, ..Default::default() };
+        let data: Vec<u8> = (0..=255).collect();
+
+        let mut link1 = Link::new(impairment.clone(), 42);
+        let mut link2 = Link::new(impairment.clone(), 42);
+        send_all(&mut link1, Duration::ZERO, &data);
+        send_all(&mut link2, Duration::ZERO, &data);
+
+        let received = receive_all(&mut link1, Duration::ZERO);
+        assert_ne!(received, data);
+        assert_eq!(received, receive_all(&mut link2, Duration::ZERO));
+    }
+}
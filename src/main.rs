@@ -1,30 +1,31 @@
-use std::time::Instant;
-use std::{io, thread};
+use std::time::Duration;
+use std::io;
+use std::process::ExitCode;
 use std::cell::RefCell;
 use std::io::{Write};
-use std::collections::{HashMap};
-use std::fmt::format;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
-use crate::avr_net::AvrNetMessage;
-use crate::config::MycochipConfig;
-use crate::network::NetworkReceive;
-use crate::server_node::ServerNode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use mycochip::{comms, config, gateway, network, scenario, websocket};
+use mycochip::avr_net::Framing;
+use mycochip::comms::REQUEST_PORT;
+use mycochip::config::MycochipConfig;
+use mycochip::events::EventPublisher;
+use mycochip::gateway::{Gateway, TcpGateway, UdpGateway};
+use mycochip::link::{Impairment, Partition};
+use mycochip::pty_bridge::PtyBridge;
+use mycochip::scenario::{Outcome, ScenarioRunner};
+use mycochip::simulation::{check_reset_lines, configure_buses, connect_device, follow_reset_lines, impair_configured_link, is_stopped, leave_out_gateways, new_avr, restart_device, run_batch, sim_time_of, take_sent, AvrReceiver, AvrSimulatorRef, Transceiver};
+use mycochip::websocket::WebSocketServer;
+use mycochip::avr_simulator::{AvrState, ResetKind};
 
 mod cli;
-mod config;
-mod comms;
-pub mod avr_simulator;
-mod network;
-mod server_node;
-mod avr_net;
 
-type AvrSimulatorRef = Rc<RefCell<avr_simulator::AvrSimulator>>;
-
-const REQUEST_PORT: i32 = 6711;
 const DEVICE_EVENT_PORT: i32 = 6712;
-const TCP_GATEWAY_NAME: &str = "tcp_gateway";
-const TCP_GATEWAY_ADDRESS: u16 = 1;
+// How long queued events may hold up exiting
+const SHUTDOWN_LINGER_MS: i32 = 1000;
 
 fn cmd_rx(channel_name: &str) {
     let context = zmq::Context::new();
@@ -39,8 +40,18 @@ fn cmd_rx(channel_name: &str) {
         subscriber.recv(&mut msg, 0).unwrap(); // Clear the topic name
         subscriber.recv(&mut msg, 0).unwrap();
 
-        let msg_bytes = &msg as &[u8];
-        let msg_str = std::str::from_utf8(msg_bytes).unwrap();
+        let event = match comms::deserialize_event(&msg) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+
+        // Only what the device sends is printed, like a serial console
+        let data = match event.kind {
+            Some(comms::event::event::Kind::BusData(bus_data)) => bus_data.data,
+            _ => continue,
+        };
+
+        let msg_str = String::from_utf8_lossy(&data);
         let msg_printable = msg_str.replace(|c: char| !c.is_ascii(), "");
 
         print!("{}", msg_printable);
@@ -48,6 +59,22 @@ fn cmd_rx(channel_name: &str) {
     }
 }
 
+fn cmd_check(config_file_path: &str, overrides: &[String]) -> ExitCode {
+    match config::check(config_file_path, overrides) {
+        Ok(config) => {
+            println!("{}: OK, {} devices", config_file_path, config.devices.len());
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            for error in &errors {
+                println!("{}", error);
+            }
+
+            ExitCode::FAILURE
+        }
+    }
+}
+
 fn cmd_list() {
     let req = comms::request::Request {
         command_type: comms::request::CommandType::List.into(),
@@ -74,139 +101,507 @@ fn cmd_pin(machine_name: &str, port: &str, pin_index: &u8, state: Option<&bool>)
     println!("Received: {}", res.as_str().unwrap());
 }
 
-struct AvrReceiver {
-    avr: AvrSimulatorRef,
+fn cmd_add(machine_name: &str, device_file_path: &str) {
+    let device = match config::load_device(device_file_path) {
+        Ok(device) => device,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+
+    let req = comms::request::Request {
+        command_type: comms::request::CommandType::AddDevice.into(),
+        args: Some(comms::request::request::Args::AddDeviceArgs(comms::request::AddDeviceArgs {
+            machine_id: machine_name.to_string(),
+            config: serde_yaml::to_string(&device).unwrap(),
+        })),
+    };
+
+    let res = comms::send_request(&req).unwrap();
+
+    println!("Received: {}", res.as_str().unwrap());
 }
 
-impl<'a> NetworkReceive<'a> for AvrReceiver {
-    fn receive(&mut self, b: u8) {
-        self.avr.borrow_mut().write_uart('0', b);
-    }
+fn cmd_remove(machine_name: &str) {
+    let req = comms::request::Request {
+        command_type: comms::request::CommandType::RemoveDevice.into(),
+        args: Some(comms::request::request::Args::RemoveDeviceArgs(comms::request::RemoveDeviceArgs {
+            machine_id: machine_name.to_string(),
+        })),
+    };
+
+    let res = comms::send_request(&req).unwrap();
+
+    println!("Received: {}", res.as_str().unwrap());
 }
 
-struct TcpReceiver {
-    // Parses messages from the chips in the network
-    avr_net_node: avr_net::AvrNetState,
-    tcp_server: Arc<ServerNode>,
+fn cmd_link(command_type: comms::request::CommandType, machine_name: &str, peer_name: &str, one_way: bool) {
+    let req = comms::request::Request {
+        command_type: command_type.into(),
+        args: Some(comms::request::request::Args::LinkArgs(comms::request::LinkArgs {
+            machine_id: machine_name.to_string(),
+            peer_id: peer_name.to_string(),
+            one_way,
+        })),
+    };
+
+    let res = comms::send_request(&req).unwrap();
+
+    println!("Received: {}", res.as_str().unwrap());
 }
 
-impl TcpReceiver {
-    fn new(tcp_server: Arc<ServerNode>) -> Self {
+fn cmd_impair(machine_name: &str, peer_name: &str, impairment: &Impairment) {
+    let req = comms::request::Request {
+        command_type: comms::request::CommandType::Impair.into(),
+        args: Some(comms::request::request::Args::ImpairArgs(comms::request::ImpairArgs {
+            machine_id: machine_name.to_string(),
+            peer_id: peer_name.to_string(),
+            config: serde_yaml::to_string(impairment).unwrap(),
+        })),
+    };
+
+    let res = comms::send_request(&req).unwrap();
+
+    println!("Received: {}", res.as_str().unwrap());
+}
+
+fn cmd_reset(machine_name: &str, kind: comms::request::ResetKind) {
+    let req = comms::request::Request {
+        command_type: comms::request::CommandType::Reset.into(),
+        args: Some(comms::request::request::Args::ResetArgs(comms::request::ResetArgs {
+            machine_id: machine_name.to_string(),
+            kind: kind.into(),
+        })),
+    };
+
+    let res = comms::send_request(&req).unwrap();
+
+    println!("Received: {}", res.as_str().unwrap());
+}
+
+fn parse_partition(partition_str: &str) -> Option<Partition> {
+    let (start_ms, duration_ms) = partition_str.split_once(':')?;
+
+    Some(Partition {
+        start_ms: start_ms.parse().ok()?,
+        duration_ms: duration_ms.parse().ok()?,
+    })
+}
+
+// The nodes of the network, along with what each kind of node needs to run
+struct Nodes {
+    network: network::Network<'static>,
+    devs: HashMap<String, AvrSimulatorRef>,
+    avr_configs: HashMap<String, config::AvrDevice>,
+    transceivers: HashMap<String, Transceiver>,
+    pty_bridges: HashMap<String, PtyBridge>,
+    gateways: HashMap<String, Box<dyn Gateway>>,
+    framing: Framing,
+}
+
+impl Nodes {
+    fn new(framing: Framing) -> Self {
         Self {
-            avr_net_node: avr_net::AvrNetState::new(TCP_GATEWAY_ADDRESS),
-            tcp_server,
+            network: network::Network::new(),
+            devs: HashMap::new(),
+            avr_configs: HashMap::new(),
+            transceivers: HashMap::new(),
+            pty_bridges: HashMap::new(),
+            gateways: HashMap::new(),
+            framing,
         }
     }
 }
 
-impl NetworkReceive<'_> for TcpReceiver {
-    fn receive(&mut self, b: u8) {
-        let data = vec![b];
-        for id in self.tcp_server.connected_client_ids() {
-            self.tcp_server.send_data(id, &data);
+fn create_device(nodes: &mut Nodes, device_name: &str, device: &config::Device) -> Result<(), String> {
+    if nodes.network.has_node(device_name) {
+        return Err(format!("Node already exists: {}", device_name));
+    }
+
+    match device {
+        config::Device::Avr(avr_device) => create_avr(nodes, device_name, avr_device),
+        config::Device::Tcp(tcp_device) => create_tcp_gateway(&mut nodes.network, &mut nodes.gateways, nodes.framing, device_name, tcp_device),
+        config::Device::Udp(udp_device) => create_udp_gateway(&mut nodes.network, &mut nodes.gateways, nodes.framing, device_name, udp_device),
+    }
+}
+
+fn create_avr(nodes: &mut Nodes, device_name: &str, device: &config::AvrDevice) -> Result<(), String> {
+    let avr = Rc::new(RefCell::new(new_avr(device_name, device)?));
+
+    let transceiver = match device.driver_enable {
+        Some(driver_enable) => Some(Transceiver::new(driver_enable, &mut avr.borrow_mut())
+            .map_err(|e| format!("{}: {}", device_name, e))?),
+        None => None,
+    };
+
+    check_reset_lines(device_name, &avr.borrow(), device, &nodes.devs, &nodes.avr_configs)?;
+
+    // Opened before the device joins the network so that a failure leaves nothing behind
+    let pty_bridge = match &device.pty {
+        Some(pty) => {
+            if !avr.borrow().has_uart(pty.uart) {
+                return Err(format!("{} has no UART{} to bridge", device_name, pty.uart));
+            }
+
+            let bridge = PtyBridge::open(pty.uart, pty.link.as_deref())
+                .map_err(|e| format!("Cannot open a pty for {}: {}", device_name, e))?;
+
+            match &pty.link {
+                Some(link) => println!("UART{} of {} is at {} ({})", pty.uart, device_name, bridge.path(), link),
+                None => println!("UART{} of {} is at {}", pty.uart, device_name, bridge.path()),
+            }
+
+            Some(bridge)
         }
+        None => None,
+    };
+
+    let avr_receiver = AvrReceiver { avr: avr.clone() };
+    nodes.network.create_node(device_name, avr_receiver);
+
+    if let Some(address) = device.address {
+        nodes.network.set_address(device_name, address)?;
+    }
+
+    nodes.devs.insert(device_name.to_string(), avr);
+    nodes.avr_configs.insert(device_name.to_string(), device.clone());
+
+    if let Some(transceiver) = transceiver {
+        nodes.transceivers.insert(device_name.to_string(), transceiver);
+    }
+
+    if let Some(pty_bridge) = pty_bridge {
+        nodes.pty_bridges.insert(device_name.to_string(), pty_bridge);
     }
+
+    println!("Started a {0} named {1}", device.mcu, device_name);
+
+    Ok(())
 }
 
-fn init_network(network: &mut network::Network, devs: &mut HashMap<String, AvrSimulatorRef>, config: &MycochipConfig) {
-    for (device_name, device) in &config.devices {
-        let eeprom = match &device.eeprom {
-            Some(eeprom) => Some(eeprom.as_slice()),
-            None => None,
-        };
+fn create_tcp_gateway(
+    network: &mut network::Network,
+    gateways: &mut HashMap<String, Box<dyn Gateway>>,
+    framing: Framing,
+    gateway_name: &str,
+    device: &config::TcpDevice,
+) -> Result<(), String> {
+    let idle_timeout = device.idle_timeout_ms.map(Duration::from_millis);
+    let gateway = TcpGateway::start(device.port, device.mode, framing, device.per_connection, idle_timeout)
+        .map_err(|e| format!("Cannot listen on TCP port {}: {}", device.port, e))?;
+
+    network.create_node(gateway_name, gateway.receiver());
+    network.set_address(gateway_name, gateway::GATEWAY_ADDRESS)?;
+
+    gateways.insert(gateway_name.to_string(), Box::new(gateway));
+
+    println!("Listening on TCP port {0} as {1}", device.port, gateway_name);
+
+    Ok(())
+}
+
+fn create_udp_gateway(
+    network: &mut network::Network,
+    gateways: &mut HashMap<String, Box<dyn Gateway>>,
+    framing: Framing,
+    gateway_name: &str,
+    device: &config::UdpDevice,
+) -> Result<(), String> {
+    let gateway = UdpGateway::start(device.port, device.mode, framing, device.remote)
+        .map_err(|e| format!("Cannot listen on UDP port {}: {}", device.port, e))?;
+
+    network.create_node(gateway_name, gateway.receiver());
+    network.set_address(gateway_name, gateway::GATEWAY_ADDRESS)?;
 
-        let avr = Rc::new(RefCell::new(avr_simulator::AvrSimulator::new(
-            &device.mcu,
-            u32::MAX,
-            &device.firmware,
-            eeprom,
-        )));
+    gateways.insert(gateway_name.to_string(), Box::new(gateway));
 
-        let avr_receiver = AvrReceiver { avr: avr.clone() };
-        network.create_node(device_name, avr_receiver);
+    println!("Listening on UDP port {0} as {1}", device.port, gateway_name);
 
-        devs.insert(device_name.clone(), avr);
+    Ok(())
+}
+
+// Data from outside is framed for the device behind the gateway unless an address is
+// configured, so this has to wait until the gateway is connected
+fn bind_gateway_address(network: &network::Network, gateways: &HashMap<String, Box<dyn Gateway>>, gateway_name: &str, device: &config::Device) {
+    let configured_address = match device {
+        config::Device::Avr(_) => return,
+        config::Device::Tcp(tcp_device) => tcp_device.address,
+        config::Device::Udp(udp_device) => udp_device.address,
+    };
 
-        println!("Started a {0} named {1}", device.mcu, device_name);
+    let address = configured_address
+        .or(network.peer_addresses(gateway_name).first().copied())
+        .unwrap_or(gateway::DEFAULT_CLIENT_ADDRESS);
+
+    if let Some(gateway) = gateways.get(gateway_name) {
+        gateway.bind_address(address);
     }
+}
+
+fn destroy_device(nodes: &mut Nodes, device_name: &str) -> Result<(), String> {
+    if !nodes.devs.contains_key(device_name) && !nodes.gateways.contains_key(device_name) {
+        return Err(format!("Unknown device: {}", device_name));
+    }
+
+    nodes.network.destroy_node(device_name)?;
+    nodes.devs.remove(device_name);
+    nodes.avr_configs.remove(device_name);
+    nodes.transceivers.remove(device_name);
+    nodes.pty_bridges.remove(device_name);
+
+    if let Some(gateway) = nodes.gateways.remove(device_name) {
+        gateway.shutdown();
+    }
+
+    println!("Stopped {}", device_name);
+
+    Ok(())
+}
+
+fn init_network(nodes: &mut Nodes, config: &MycochipConfig) {
+    let network = &mut nodes.network;
+
+    if config.routing == config::Routing::AvrNet {
+        let frame_timeout = config.frame_timeout_us.map(Duration::from_micros);
+        network.enable_routing(config.framing, frame_timeout);
+    }
+
+    for (device_name, device) in &config.devices {
+        if let Err(err) = create_device(nodes, device_name, device) {
+            println!("Error: {}", err);
+        }
+    }
+
+    let network = &mut nodes.network;
 
     // Connect the network
     for (device_name, device) in &config.devices {
-        for peer_name in &device.peers {
-            network.connect(device_name, peer_name);
+        if let Err(err) = connect_device(network, device_name, device) {
+            println!("Error: {}", err);
         }
     }
+
+    if let Err(err) = configure_buses(network, config) {
+        println!("Error: {}", err);
+    }
+
+    for (device_name, device) in &config.devices {
+        bind_gateway_address(network, &nodes.gateways, device_name, device);
+    }
+
+    network.set_seed(config.seed);
+
+    for link in &config.links {
+        if let Err(err) = impair_configured_link(network, link) {
+            println!("Error: {}", err);
+        }
+    }
+}
+
+fn add_device(nodes: &mut Nodes, args: &comms::request::AddDeviceArgs) -> Result<(), String> {
+    let device: config::Device = serde_yaml::from_str(&args.config)
+        .map_err(|e| format!("Invalid device config: {}", e))?;
+
+    create_device(nodes, &args.machine_id, &device)?;
+
+    // Don't leave a half-connected device behind
+    if let Err(err) = connect_device(&mut nodes.network, &args.machine_id, &device) {
+        destroy_device(nodes, &args.machine_id)?;
+        return Err(err);
+    }
+
+    bind_gateway_address(&nodes.network, &nodes.gateways, &args.machine_id, &device);
+
+    Ok(())
 }
 
-fn publish_bus_data(node_name: &str, publisher: &zmq::Socket, data: &Vec<u8>) -> Result<(), zmq::Error> {
-    let topic = format!("{}/bus", node_name);
-    publisher.send(topic.as_bytes(), zmq::SNDMORE)?;
-    publisher.send(data, 0)
+fn impair_link(network: &mut network::Network, args: &comms::request::ImpairArgs) -> Result<(), String> {
+    let mut impairment: Impairment = serde_yaml::from_str(&args.config)
+        .map_err(|e| format!("Invalid impairment config: {}", e))?;
+
+    // Partitions requested at runtime are relative to the current simulated time
+    let now_ms = network.time().as_millis() as u64;
+    for partition in &mut impairment.partitions {
+        partition.start_ms += now_ms;
+    }
+
+    network.impair(&args.machine_id, &args.peer_id, impairment.clone())?;
+    network.impair(&args.peer_id, &args.machine_id, impairment)
 }
 
-fn publish_pin_event(node_name: &str, publisher: &zmq::Socket, port: char, pin_index: u8, state: bool) -> Result<(), zmq::Error> {
-    let topic = format!("{}/pin/{}/{}", node_name, port, pin_index);
-    publisher.send(topic.as_bytes(), zmq::SNDMORE)?;
-    publisher.send( (state as u8).to_string().as_bytes(), 0)
+fn reset_device(devs: &HashMap<String, AvrSimulatorRef>, args: &comms::request::ResetArgs) -> Result<(), String> {
+    let dev = devs.get(&args.machine_id).ok_or(format!("Unknown device: {}", args.machine_id))?;
+
+    let kind = match comms::request::ResetKind::from_i32(args.kind) {
+        Some(comms::request::ResetKind::External) => ResetKind::External,
+        Some(comms::request::ResetKind::PowerOn) => ResetKind::PowerOn,
+        Some(comms::request::ResetKind::Watchdog) => ResetKind::Watchdog,
+        None => return Err(format!("Unknown reset kind: {}", args.kind)),
+    };
+
+    dev.borrow_mut().reset(kind);
+
+    Ok(())
 }
 
-struct PinTracker {
-    last_port_values: HashMap<char, u8>,
+fn response_for(result: Result<(), String>) -> String {
+    match result {
+        Ok(()) => "OK".to_string(),
+        Err(err) => format!("Error: {}", err),
+    }
 }
 
-impl PinTracker {
-    fn new() -> Self {
-        Self {
-            // TODO: allow for parts with different number of ports
-            last_port_values: HashMap::from([
-                ('B', 0),
-                ('C', 0),
-                ('D', 0),
-            ]),
+// Carry out a command from a dashboard
+fn run_command(devs: &HashMap<String, AvrSimulatorRef>, command: websocket::Command) -> Result<(), String> {
+    match command {
+        websocket::Command::Uart { node, uart, data } => {
+            let dev = &mut *devs.get(&node).ok_or(format!("Unknown device: {}", node))?.borrow_mut();
+
+            if !dev.has_uart(uart) {
+                return Err(format!("{} has no UART{}", node, uart));
+            }
+
+            for b in data {
+                dev.write_uart(uart, b);
+            }
         }
-    }
+        websocket::Command::Pin { node, port, pin, state } => {
+            let dev = &mut *devs.get(&node).ok_or(format!("Unknown device: {}", node))?.borrow_mut();
 
-    fn update(&mut self, avr: &mut avr_simulator::AvrSimulator) -> Vec<(char, u8, bool)> {
-        let mut pin_events: Vec<(char, u8, bool)> = vec![];
-        let mut new_values: HashMap<char, u8> = HashMap::new();
+            if !dev.ports().contains(&port) || pin > 7 {
+                return Err(format!("{} has no pin P{}{}", node, port, pin));
+            }
 
-        for (port_name, last_port_value) in self.last_port_values.iter_mut() {
-            // TODO: retrieve the whole port at once
-            let mut port_value = 0;
+            dev.set_digital_pin(port, pin, state);
+        }
+    }
 
-            for bit_idx in 0..8 {
-                let last_state = ((*last_port_value >> bit_idx) & 1) > 0;
-                let current_state = avr.get_digital_pin(*port_name, bit_idx);
+    Ok(())
+}
 
-                if last_state != current_state {
-                    pin_events.push((*port_name, bit_idx, current_state));
-                }
+// Answer a request, and make failures visible to event subscribers as well
+fn respond(responder: &zmq::Socket, events: &EventPublisher, sim_time: Duration, result: Result<(), String>) {
+    if let Err(err) = &result {
+        events.log("", sim_time, &format!("Error: {}", err)).unwrap();
+    }
 
-                port_value |= (current_state as u8) << bit_idx;
-            }
+    responder.send(response_for(result).as_str(), 0).unwrap();
+}
 
-            new_values.insert(*port_name, port_value);
+// Running and sleeping alternate all the time, so only changes to or from other states are
+// worth telling anyone about
+fn is_notable_change(previous_state: AvrState, state: AvrState) -> bool {
+    let is_active = |state| state == AvrState::Running || state == AvrState::Sleeping;
+    previous_state != state && !(is_active(previous_state) && is_active(state))
+}
+
+fn cmd_test(scenario_file_path: &str, overrides: &[String], headless: bool) -> ExitCode {
+    let scenario = match scenario::load(scenario_file_path) {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            println!("Error: cannot load scenario {}: {}", scenario_file_path, err);
+            return ExitCode::FAILURE;
         }
+    };
+
+    // Overrides on the command line take precedence over those in the scenario
+    let config_file_path = scenario.config.clone();
+    let overrides: Vec<String> = scenario.set.iter().chain(overrides).cloned().collect();
+
+    cmd_up(&config_file_path, UpOptions {
+        overrides,
+        scenario: Some(ScenarioRunner::new(scenario)),
+        headless,
+        ..Default::default()
+    })
+}
 
-        self.last_port_values = new_values;
+// How the network is brought up, beyond what the config says
+#[derive(Default)]
+struct UpOptions {
+    overrides: Vec<String>,
+    scenario: Option<ScenarioRunner>,
 
-        pin_events
+    // Bind no sockets and leave out gateways, for machines where listening isn't allowed
+    headless: bool,
+
+    // Stop after this much simulated time
+    duration: Option<Duration>,
+
+    // Stop once every device has finished or crashed
+    until_done: bool,
+
+    // Stop once any device has sent this on the network
+    until_uart: Option<String>,
+
+    // Collect what each device sends on the network and write it to `<name>.log` in this
+    // directory, or to stdout when it's empty
+    output_dir: Option<PathBuf>,
+}
+
+fn cmd_run(config_file_path: &str, options: UpOptions) -> ExitCode {
+    if options.duration.is_none() && !options.until_done && options.until_uart.is_none() {
+        println!("Error: a run needs --duration, --until-done or --until-uart to know when to stop");
+        return ExitCode::FAILURE;
     }
+
+    cmd_up(config_file_path, options)
 }
 
-fn cmd_up(config_file_path: &str) {
-    let config_or_err = config::load(config_file_path);
+// Parse a simulated duration like "500ms", "2s" or "100us"
+fn parse_duration(duration_str: &str) -> Option<Duration> {
+    let (number, unit_secs) = [("us", 1e-6), ("ms", 1e-3), ("s", 1.0)]
+        .iter()
+        .find_map(|(unit, unit_secs)| duration_str.strip_suffix(unit).map(|number| (number, *unit_secs)))?;
+
+    let secs = number.trim().parse::<f64>().ok()? * unit_secs;
 
-    if config_or_err.is_err() {
-        println!("Error: {}", config_or_err.err().unwrap());
-        return;
+    match secs.is_finite() && secs >= 0.0 {
+        true => Some(Duration::from_secs_f64(secs)),
+        false => None,
     }
+}
 
-    let config = config::load(config_file_path).unwrap();
+fn write_output(output_dir: &Path, outputs: &HashMap<String, Vec<u8>>) -> io::Result<()> {
+    let mut device_names: Vec<&String> = outputs.keys().collect();
+    device_names.sort();
 
-    let mut devs: HashMap<String, AvrSimulatorRef> = HashMap::new();
-    let mut pin_trackers: HashMap<String, PinTracker> = HashMap::new();
-    let mut network = network::Network::new();
+    if output_dir.as_os_str().is_empty() {
+        for device_name in device_names {
+            println!("==> {} <==", device_name);
+            io::stdout().write_all(&outputs[device_name])?;
+            println!();
+        }
+
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(output_dir)?;
+
+    for device_name in device_names {
+        std::fs::write(output_dir.join(format!("{}.log", device_name)), &outputs[device_name])?;
+    }
+
+    Ok(())
+}
+
+fn cmd_up(config_file_path: &str, options: UpOptions) -> ExitCode {
+    let mut config = match config::load(config_file_path, &options.overrides) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Error: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut scenario = options.scenario;
+
+    if options.headless {
+        leave_out_gateways(&mut config);
+    }
+
+    let mut nodes = Nodes::new(config.framing);
 
     // ZMQ sockets
     let context = zmq::Context::new();
@@ -214,99 +609,233 @@ fn cmd_up(config_file_path: &str) {
     responder.set_linger(0).unwrap();
     let publisher = context.socket(zmq::PUB).unwrap();
     publisher.set_linger(0).unwrap();
+    let events = EventPublisher::new(publisher);
 
-    {
+    // Without bound sockets, no requests arrive and events go nowhere
+    if !options.headless {
         let responder_address = format!("tcp://*:{}", REQUEST_PORT);
         assert!(responder.bind(responder_address.as_str()).is_ok());
         let pub_address = format!("tcp://*:{}", DEVICE_EVENT_PORT);
         println!("Publishing on {}", pub_address);
-        assert!(publisher.bind(pub_address.as_str()).is_ok());
+        assert!(events.socket().bind(pub_address.as_str()).is_ok());
     }
 
-    // TCP server
-    let tcp_server_for_rx = Arc::new(ServerNode::new("0.0.0.0:7001"));
-    let tcp_server_for_tx = tcp_server_for_rx.clone();
+    let running = Arc::new(AtomicBool::new(true));
     {
-        let tcp_server = tcp_server_for_rx.clone();
-        thread::spawn(move || tcp_server.start());
-    }
-
-    {
-        let tcp_server = tcp_server_for_rx.clone();
+        let running = running.clone();
         ctrlc::set_handler(move || {
-            println!("Shutting down");
-            tcp_server.shutdown();
-            std::process::exit(0);
+            // A second Ctrl-C gives up on shutting down cleanly
+            if !running.swap(false, Ordering::Relaxed) {
+                std::process::exit(1);
+            }
         })
         .expect("Error setting Ctrl-C handler");
     }
 
-    let tcp_receiver = TcpReceiver::new(tcp_server_for_tx);
-    network.create_node(TCP_GATEWAY_NAME, tcp_receiver);
-    init_network(&mut network, &mut devs, &config);
+    let mut websocket_server = match &config.websocket {
+        Some(websocket_config) => match WebSocketServer::bind(websocket_config.port) {
+            Ok(server) => {
+                println!("Serving WebSocket on port {}", websocket_config.port);
+                Some(server)
+            }
+            Err(err) => {
+                println!("Error: cannot serve WebSocket on port {}: {}", websocket_config.port, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
 
-    for (device_name, _) in &devs {
-        pin_trackers.insert(device_name.clone(), PinTracker::new());
-    }
+    init_network(&mut nodes, &config);
+
+    let mut crashed: HashSet<String> = HashSet::new();
+    let mut device_states: HashMap<String, AvrState> = HashMap::new();
+    let mut held_in_reset: HashSet<String> = HashSet::new();
+    let mut scenario_outcome = Outcome::Running;
+    let mut outputs: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut uart_seen = false;
 
     let mut msg = zmq::Message::new();
     loop {
-        let now = Instant::now();
-
         // Collect messages sent from the devices
-        for (device_name, dev) in devs.iter_mut() {
-            let data: Vec<u8> = std::iter::from_fn(|| dev.borrow_mut().read_uart('0')).collect();
+        for (device_name, dev) in nodes.devs.iter_mut() {
+            let sent = take_sent(device_name, dev, &mut nodes.transceivers);
 
-            if data.len() == 0 {
+            if sent.is_empty() {
                 continue;
             }
 
-            network.broadcast_from(device_name, &data);
+            let data: Vec<u8> = sent.iter().map(|(_, b)| *b).collect();
+
+            // A pty on UART0 sees the same bytes as the network
+            if let Some(pty_bridge) = nodes.pty_bridges.get_mut(device_name).filter(|bridge| bridge.uart() == '0') {
+                pty_bridge.write(&data);
+            }
+
+            nodes.network.broadcast_at(device_name, &sent);
+
+            if let Some(scenario) = &mut scenario {
+                scenario.receive(device_name, &data);
+            }
+
+            if options.output_dir.is_some() || options.until_uart.is_some() {
+                let output = outputs.entry(device_name.clone()).or_default();
+                output.extend_from_slice(&data);
+
+                // Only the new data, and what came just before it, can complete the pattern
+                if let Some(pattern) = &options.until_uart {
+                    let start = output.len().saturating_sub(data.len() + pattern.len());
+                    uart_seen |= output[start..].windows(pattern.len().max(1)).any(|window| window == pattern.as_bytes());
+                }
+            }
 
             // Publish for external listeners
-            publish_bus_data(device_name, &publisher, &data).unwrap();
+            events.bus_data(device_name, sim_time_of(dev), &data).unwrap();
+
+            if let Some(websocket_server) = &mut websocket_server {
+                websocket_server.broadcast(&websocket::Event::Bus { node: device_name.clone(), data });
+            }
         }
 
-        // Collect messages sent from the world to the devices
-        let client_ids = tcp_server_for_rx.connected_client_ids();
-        for id in client_ids {
-            if let Some(buf) = tcp_server_for_rx.read_data(id) {
-                let tcp_data = buf;
+        // Exchange data with the host's serial tools
+        for (device_name, pty_bridge) in nodes.pty_bridges.iter_mut() {
+            let dev = &mut *nodes.devs[device_name].borrow_mut();
+
+            if pty_bridge.uart() != '0' {
+                let data: Vec<u8> = std::iter::from_fn(|| dev.read_uart(pty_bridge.uart())).collect();
+                pty_bridge.write(&data);
+            }
+
+            for b in pty_bridge.read() {
+                dev.write_uart(pty_bridge.uart(), b);
+            }
+        }
 
-                let avr_net_message = Vec::try_from(AvrNetMessage {
-                    address: 1, // HACK: hard-coded address
-                    data: tcp_data.clone(),
-                }).unwrap();
+        // Collect messages sent from the world to the devices
+        for (gateway_name, gateway) in nodes.gateways.iter() {
+            let data = gateway.collect();
 
-                println!("Sending message of size {} to AVR", avr_net_message.len());
+            if data.len() > 0 {
+                nodes.network.broadcast_from(gateway_name, &data);
+            }
 
-                network.broadcast_from(TCP_GATEWAY_NAME, &avr_net_message);
-                println!("Received: {}", String::from_utf8_lossy(&tcp_data));
+            for (client_id, connected) in gateway.connection_changes() {
+                events.gateway_connection(gateway_name, nodes.network.time(), client_id, connected).unwrap();
             }
         }
 
-        // Deliver queued messages
-        network.deliver_messages();
+        // Deliver queued messages, as of the time of the device that is furthest behind, leaving
+        // out stopped devices so that they don't hold everyone else back
+        let sim_time = nodes.devs.values()
+            .filter(|dev| !is_stopped(dev.borrow().state()))
+            .map(sim_time_of)
+            .min();
+
+        if let Some(sim_time) = sim_time {
+            nodes.network.set_time(sim_time);
+        }
+
+        nodes.network.deliver_messages();
+
+        // Stopping here means that what the devices sent last has made it through the network
+        if !running.load(Ordering::Relaxed) {
+            break;
+        }
 
         // Update the AVRs
-        for _ in 1..1000 {
-            for (_, dev) in devs.iter() {
-                let _state = dev.borrow_mut().step();
+        let pin_changes = run_batch(&nodes.devs, &mut nodes.transceivers, &held_in_reset);
+
+        // Broadcast state changes
+        for (node_name, dev) in nodes.devs.iter() {
+            let sim_time = sim_time_of(dev);
+            let state = dev.borrow().state();
+            let previous_state = device_states.insert(node_name.clone(), state).unwrap_or(AvrState::Running);
+
+            if !is_notable_change(previous_state, state) {
+                continue;
+            }
+
+            let (pc, cycle) = {
+                let dev = dev.borrow();
+                (dev.pc(), dev.cycle())
+            };
+
+            events.state_change(node_name, sim_time, previous_state, state, pc, cycle).unwrap();
+
+            if !is_stopped(state) {
+                continue;
+            }
+
+            let verb = match state {
+                AvrState::Crashed => "crashed",
+                _ => "finished",
+            };
+
+            events.log(node_name, sim_time, &format!("{} {} at pc 0x{:x} after {} cycles", node_name, verb, pc, cycle)).unwrap();
+
+            if state == AvrState::Crashed {
+                crashed.insert(node_name.clone());
+            }
 
-                if _state.state == avr_simulator::state::AvrState::Sleeping {
-                    break;
+            let avr_config = &nodes.avr_configs[node_name];
+
+            match avr_config.on_stop {
+                config::StopPolicy::Ignore => {}
+                config::StopPolicy::Restart => {
+                    match restart_device(node_name, dev, avr_config, &mut nodes.transceivers) {
+                        Ok(()) => {
+                            device_states.remove(node_name);
+                            events.log(node_name, sim_time, &format!("Restarted {}", node_name)).unwrap();
+                        }
+                        Err(err) => events.log(node_name, sim_time, &format!("Error: {}", err)).unwrap(),
+                    }
+                }
+                config::StopPolicy::HaltNetwork => {
+                    running.store(false, Ordering::Relaxed);
                 }
             }
         }
 
-        // Broadcast pin events
-        for (node_name, dev) in devs.iter_mut() {
-            let sim = &mut *dev.borrow_mut();
-            let pin_tracker = pin_trackers.get_mut(node_name).unwrap();
-            let pin_events = pin_tracker.update(sim);
+        // Broadcast pin events, each at the time the pin actually changed
+        for (node_name, changes) in &pin_changes {
+            for change in changes {
+                let sim_time = Duration::from_secs_f64(change.at.as_secs_f64());
+                events.pin_change(node_name, sim_time, change.port, change.pin, change.high).unwrap();
 
-            for (port, pin_index, state) in pin_events {
-                publish_pin_event(node_name, &publisher, port, pin_index, state).unwrap();
+                if let Some(websocket_server) = &mut websocket_server {
+                    websocket_server.broadcast(&websocket::Event::Pin { node: node_name.clone(), port: change.port, pin: change.pin, state: change.high });
+                }
+            }
+        }
+
+        for (device_name, message) in follow_reset_lines(&nodes.devs, &nodes.avr_configs, &pin_changes, &mut held_in_reset) {
+            events.log(&device_name, sim_time_of(&nodes.devs[&device_name]), &message).unwrap();
+        }
+
+        // Work through the scenario being tested, stopping once it has passed or failed
+        if let Some(scenario) = &mut scenario {
+            scenario_outcome = scenario.poll(nodes.network.time(), &nodes.devs);
+
+            if scenario_outcome != Outcome::Running {
+                running.store(false, Ordering::Relaxed);
+            }
+        }
+
+        // Stop a bounded run once it has got where it was meant to
+        let is_finished = options.duration.is_some_and(|duration| nodes.network.time() >= duration)
+            || (options.until_done && nodes.devs.values().all(|dev| is_stopped(dev.borrow().state())))
+            || uart_seen;
+
+        if is_finished {
+            running.store(false, Ordering::Relaxed);
+        }
+
+        // Take commands from dashboards
+        if let Some(websocket_server) = &mut websocket_server {
+            for (client_id, command) in websocket_server.poll() {
+                if let Err(err) = command.and_then(|command| run_command(&nodes.devs, command)) {
+                    websocket_server.send_to(client_id, &websocket::Event::Error { message: err });
+                }
             }
         }
 
@@ -318,7 +847,13 @@ fn cmd_up(config_file_path: &str) {
 
             match comms::request::CommandType::from_i32(req.command_type) {
                 Some(comms::request::CommandType::List) => {
-                    responder.send("device1, device2", 0).unwrap();
+                    let mut device_names: Vec<String> = nodes.devs.iter()
+                        .map(|(name, dev)| format!("{} ({})", name, dev.borrow().state()))
+                        .chain(nodes.gateways.keys().cloned())
+                        .collect();
+                    device_names.sort();
+                    let device_list = device_names.join(", ");
+                    responder.send(device_list.as_str(), 0).unwrap();
                 },
                 Some(comms::request::CommandType::Logs) => {
                     responder.send("hello from the logs", 0).unwrap();
@@ -327,7 +862,7 @@ fn cmd_up(config_file_path: &str) {
                     match req.args {
                         Some(comms::request::request::Args::IoArgs(ref io_args)) => {
                             let port_char: char = io_args.port.chars().next().unwrap();
-                            let pin_state = devs.get(&io_args.machine_id)
+                            let pin_state = nodes.devs.get(&io_args.machine_id)
                                 .unwrap().borrow_mut()
                                 .get_digital_pin(port_char, io_args.pin_index as u8);
                             let pin_msg = format!("{}", pin_state);
@@ -338,6 +873,70 @@ fn cmd_up(config_file_path: &str) {
                         },
                     }
                 }
+                Some(comms::request::CommandType::AddDevice) => {
+                    let result = match req.args {
+                        Some(comms::request::request::Args::AddDeviceArgs(ref add_args)) => {
+                            add_device(&mut nodes, add_args)
+                        },
+                        _ => Err("Missing arguments".to_string()),
+                    };
+
+                    respond(&responder, &events, nodes.network.time(), result);
+                }
+                Some(comms::request::CommandType::RemoveDevice) => {
+                    let result = match req.args {
+                        Some(comms::request::request::Args::RemoveDeviceArgs(ref remove_args)) => {
+                            destroy_device(&mut nodes, &remove_args.machine_id)
+                        },
+                        _ => Err("Missing arguments".to_string()),
+                    };
+
+                    respond(&responder, &events, nodes.network.time(), result);
+                }
+                Some(comms::request::CommandType::Connect) => {
+                    let result = match req.args {
+                        Some(comms::request::request::Args::LinkArgs(ref link_args)) if link_args.one_way => {
+                            nodes.network.connect_one_way(&link_args.machine_id, &link_args.peer_id)
+                        },
+                        Some(comms::request::request::Args::LinkArgs(ref link_args)) => {
+                            nodes.network.connect(&link_args.machine_id, &link_args.peer_id)
+                        },
+                        _ => Err("Missing arguments".to_string()),
+                    };
+
+                    respond(&responder, &events, nodes.network.time(), result);
+                }
+                Some(comms::request::CommandType::Disconnect) => {
+                    let result = match req.args {
+                        Some(comms::request::request::Args::LinkArgs(ref link_args)) if link_args.one_way => {
+                            nodes.network.disconnect_one_way(&link_args.machine_id, &link_args.peer_id)
+                        },
+                        Some(comms::request::request::Args::LinkArgs(ref link_args)) => {
+                            nodes.network.disconnect(&link_args.machine_id, &link_args.peer_id)
+                        },
+                        _ => Err("Missing arguments".to_string()),
+                    };
+
+                    respond(&responder, &events, nodes.network.time(), result);
+                }
+                Some(comms::request::CommandType::Reset) => {
+                    let result = match req.args {
+                        Some(comms::request::request::Args::ResetArgs(ref reset_args)) => reset_device(&nodes.devs, reset_args),
+                        _ => Err("Missing arguments".to_string()),
+                    };
+
+                    respond(&responder, &events, nodes.network.time(), result);
+                }
+                Some(comms::request::CommandType::Impair) => {
+                    let result = match req.args {
+                        Some(comms::request::request::Args::ImpairArgs(ref impair_args)) => {
+                            impair_link(&mut nodes.network, impair_args)
+                        },
+                        _ => Err("Missing arguments".to_string()),
+                    };
+
+                    respond(&responder, &events, nodes.network.time(), result);
+                }
                 _ => {
                     responder.send("Error!", 0).unwrap();
                 },
@@ -349,9 +948,61 @@ fn cmd_up(config_file_path: &str) {
         //     println!("Elapsed: {:.2?}", elapsed);
         // }
     }
+
+    events.log("", nodes.network.time(), "Shutting down").unwrap();
+
+    // Send what is still queued for clients and then close their connections
+    for (_, gateway) in nodes.gateways.drain() {
+        gateway.shutdown();
+    }
+
+    // Removes the pty symlinks
+    nodes.pty_bridges.clear();
+
+    if let Some(output_dir) = &options.output_dir {
+        if let Err(err) = write_output(output_dir, &outputs) {
+            println!("Error: cannot write output to {}: {}", output_dir.display(), err);
+        }
+    }
+
+    let mut crashed_names: Vec<String> = crashed.into_iter().collect();
+    crashed_names.sort();
+
+    let mut exit_code = match crashed_names.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => {
+            events.log("", nodes.network.time(), &format!("Crashed: {}", crashed_names.join(", "))).unwrap();
+            ExitCode::FAILURE
+        }
+    };
+
+    if scenario.is_some() {
+        let verdict = match &scenario_outcome {
+            Outcome::Passed => "Scenario passed".to_string(),
+            Outcome::Failed(reason) => format!("Scenario failed: {}", reason),
+            Outcome::Running => "Scenario interrupted".to_string(),
+        };
+
+        events.log("", nodes.network.time(), &verdict).unwrap();
+
+        if scenario_outcome != Outcome::Passed {
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+
+    if let Some(websocket_server) = &mut websocket_server {
+        websocket_server.broadcast(&websocket::Event::Shutdown { crashed: crashed_names.clone() });
+        websocket_server.close();
+    }
+
+    // Give subscribers a chance to hear about the shutdown before the socket goes away
+    events.socket().set_linger(SHUTDOWN_LINGER_MS).unwrap();
+    events.shutdown(nodes.network.time(), crashed_names).unwrap();
+
+    exit_code
 }
 
-fn main() {
+fn main() -> ExitCode {
     let matches = cli::build_cli().get_matches();
 
     match matches.subcommand() {
@@ -361,7 +1012,53 @@ fn main() {
                 None => "mycochip.yaml",
             };
 
-            cmd_up(config_file_path);
+            let overrides: Vec<String> = args.get_many::<String>("set").unwrap_or_default().cloned().collect();
+
+            return cmd_up(config_file_path, UpOptions { overrides, ..Default::default() });
+        },
+        Some(("check", args)) => {
+            let config_file_path = match args.get_one::<String>("config-file") {
+                Some(config_file_path) => config_file_path,
+                None => "mycochip.yaml",
+            };
+
+            let overrides: Vec<String> = args.get_many::<String>("set").unwrap_or_default().cloned().collect();
+
+            return cmd_check(config_file_path, &overrides);
+        },
+        Some(("test", args)) => {
+            let scenario_file_path = args.get_one::<String>("scenario-file")
+                .expect("Scenario file is required");
+            let overrides: Vec<String> = args.get_many::<String>("set").unwrap_or_default().cloned().collect();
+
+            return cmd_test(scenario_file_path, &overrides, args.get_flag("headless"));
+        },
+        Some(("run", args)) => {
+            let config_file_path = args.get_one::<String>("config-file")
+                .expect("Config file is required");
+
+            let duration = match args.get_one::<String>("duration") {
+                Some(duration_str) => match parse_duration(duration_str) {
+                    Some(duration) => Some(duration),
+                    None => {
+                        println!("Error: invalid duration {}, expected e.g. 500ms or 2s", duration_str);
+                        return ExitCode::FAILURE;
+                    }
+                },
+                None => None,
+            };
+
+            let options = UpOptions {
+                overrides: args.get_many::<String>("set").unwrap_or_default().cloned().collect(),
+                headless: true,
+                duration,
+                until_done: args.get_flag("until-done"),
+                until_uart: args.get_one::<String>("until-uart").cloned(),
+                output_dir: Some(args.get_one::<String>("output-dir").map(PathBuf::from).unwrap_or_default()),
+                ..Default::default()
+            };
+
+            return cmd_run(config_file_path, options);
         },
         Some(("list", _)) => cmd_list(),
         Some(("pin", args)) => {
@@ -381,6 +1078,82 @@ fn main() {
 
             cmd_rx(node_name);
         },
+        Some(("add", args)) => {
+            let node_name = args.get_one::<String>("node")
+                .expect("Node name is required");
+            let device_file_path = args.get_one::<String>("device-file")
+                .expect("Device file is required");
+
+            cmd_add(node_name, device_file_path);
+        },
+        Some(("remove", args)) => {
+            let node_name = args.get_one::<String>("node")
+                .expect("Node name is required");
+
+            cmd_remove(node_name);
+        },
+        Some(("connect", args)) => {
+            let node_name = args.get_one::<String>("node")
+                .expect("Node name is required");
+            let peer_name = args.get_one::<String>("peer")
+                .expect("Peer name is required");
+
+            let one_way = args.get_flag("one-way");
+
+            cmd_link(comms::request::CommandType::Connect, node_name, peer_name, one_way);
+        },
+        Some(("disconnect", args)) => {
+            let node_name = args.get_one::<String>("node")
+                .expect("Node name is required");
+            let peer_name = args.get_one::<String>("peer")
+                .expect("Peer name is required");
+
+            let one_way = args.get_flag("one-way");
+
+            cmd_link(comms::request::CommandType::Disconnect, node_name, peer_name, one_way);
+        },
+        Some(("impair", args)) => {
+            let node_name = args.get_one::<String>("node")
+                .expect("Node name is required");
+            let peer_name = args.get_one::<String>("peer")
+                .expect("Peer name is required");
+
+            let mut partitions = vec![];
+            for partition_str in args.get_many::<String>("partition").unwrap_or_default() {
+                match parse_partition(partition_str) {
+                    Some(partition) => partitions.push(partition),
+                    None => {
+                        println!("Error: invalid partition {}, expected START_MS:DURATION_MS", partition_str);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            let impairment = Impairment {
+                drop_probability: args.get_one::<f64>("drop").copied().unwrap_or_default(),
+                bit_flip_rate: args.get_one::<f64>("bit-flip").copied().unwrap_or_default(),
+                latency_us: args.get_one::<u64>("latency").copied().unwrap_or_default(),
+                jitter_us: args.get_one::<u64>("jitter").copied().unwrap_or_default(),
+                bandwidth_bps: args.get_one::<u64>("bandwidth").copied().unwrap_or_default(),
+                partitions,
+            };
+
+            cmd_impair(node_name, peer_name, &impairment);
+        },
+        Some(("reset", args)) => {
+            let node_name = args.get_one::<String>("node")
+                .expect("Node name is required");
+
+            let kind = match args.get_one::<String>("kind").map(|s| s.as_str()) {
+                Some("power-on") => comms::request::ResetKind::PowerOn,
+                Some("watchdog") => comms::request::ResetKind::Watchdog,
+                _ => comms::request::ResetKind::External,
+            };
+
+            cmd_reset(node_name, kind);
+        },
         _ => println!("No subcommand"),
     }
+
+    ExitCode::SUCCESS
 }
@@ -1,10 +1,20 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+use crate::avr_net::{AvrNetMessage, AvrNetState, Framing, BROADCAST_ADDRESS};
+use crate::link::{Impairment, Link, link_seed};
+
+// Bit rate of a bus unless it's configured otherwise
+pub const DEFAULT_BUS_BAUD: u32 = 9600;
+
+// Bits a byte takes on a bus, with its start and stop bits
+const BUS_BITS_PER_BYTE: u64 = 10;
 
 struct NetworkNode<'a> {
     name: String,
     // The names of the nodes this node is connected to
     peers: HashSet<String>,
-    outgoing: Vec<u8>,
+    // Bytes to be delivered, along with when the node sent them
+    outgoing: Vec<(Duration, u8)>,
     receiver: Box<dyn NetworkReceive<'a> + 'a>,
 }
 
@@ -32,55 +42,308 @@ impl<'a> NetworkNode<'a> {
 
     pub fn broadcast(&mut self) -> Vec<u8> {
         let mut data = Vec::new();
-        data.extend(self.outgoing.iter());
+        data.extend(self.outgoing.iter().map(|(_, b)| *b));
 
         data
     }
 }
 
+// A shared medium, like an RS-485 bus, where every member hears everything sent on it
+struct Bus {
+    members: BTreeSet<String>,
+    collisions: u64,
+    // How long a byte occupies the bus
+    byte_time: Duration,
+    // Bytes sent by members but not put on the bus yet, as (sent at, sender name, byte)
+    pending: Vec<(Duration, String, u8)>,
+}
+
+impl Bus {
+    fn new() -> Self {
+        Self {
+            members: BTreeSet::new(),
+            collisions: 0,
+            byte_time: Self::byte_time(DEFAULT_BUS_BAUD),
+            pending: Vec::new(),
+        }
+    }
+
+    fn byte_time(baud: u32) -> Duration {
+        Duration::from_nanos(BUS_BITS_PER_BYTE * 1_000_000_000 / baud as u64)
+    }
+}
+
+// Parses the avr_net frames sent by each node so they can be delivered only where they're addressed
+struct Router {
+    framing: Framing,
+    frame_timeout: Option<Duration>,
+    // node name -> parser for the data sent by that node
+    parsers: HashMap<String, AvrNetState>,
+}
+
+impl Router {
+    fn new(framing: Framing, frame_timeout: Option<Duration>) -> Self {
+        Self {
+            framing,
+            frame_timeout,
+            parsers: HashMap::new(),
+        }
+    }
+
+    fn parse(&mut self, node_name: &str, data: &[u8], now: Duration) -> Vec<AvrNetMessage> {
+        let (framing, frame_timeout) = (self.framing, self.frame_timeout);
+        let parser = self.parsers.entry(node_name.to_string()).or_insert_with(|| {
+            AvrNetState::any_address()
+                .with_framing(framing)
+                .with_timeout(frame_timeout)
+        });
+
+        data.iter().filter_map(|b| parser.rx_at(*b, now)).collect()
+    }
+}
+
 pub struct Network<'a> {
     // The nodes in the network
     // node name -> node
     nodes: HashMap<String, NetworkNode<'a>>,
+    // Links that aren't perfect wires
+    // (sender name, receiver name) -> link
+    links: HashMap<(String, String), Link>,
+    // bus name -> bus
+    buses: HashMap<String, Bus>,
+    // node name -> avr_net address
+    addresses: HashMap<String, u16>,
+    router: Option<Router>,
+    seed: u64,
+    // Simulated time
+    time: Duration,
 }
 
 impl<'a> Network<'a> {
     pub fn new() -> Self {
         Self {
             nodes: HashMap::new(),
+            links: HashMap::new(),
+            buses: HashMap::new(),
+            addresses: HashMap::new(),
+            router: None,
+            seed: 0,
+            time: Duration::ZERO,
         }
     }
 
+    // Seed the randomness of links impaired after this is called
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    // Time never goes backwards, even if a restarted device starts counting from zero again
+    pub fn set_time(&mut self, time: Duration) {
+        self.time = self.time.max(time);
+    }
+
     pub fn create_node(&mut self, name: &str, receiver: impl NetworkReceive<'a>) {
         let new_node = NetworkNode::new(name, receiver);
         self.nodes.insert(name.to_string(), new_node);
     }
 
-    pub fn destroy_node(&mut self, name: &str) {
-        self.nodes.remove(name);
+    pub fn destroy_node(&mut self, name: &str) -> Result<(), String> {
+        if self.nodes.remove(name).is_none() {
+            return Err(format!("Unknown node: {}", name));
+        }
+
+        // Don't leave other nodes pointing at the one that was removed
+        for node in self.nodes.values_mut() {
+            node.peers.remove(name);
+        }
+
+        self.links.retain(|(from, to), _| from != name && to != name);
+
+        for bus in self.buses.values_mut() {
+            bus.members.remove(name);
+        }
+
+        self.addresses.remove(name);
+        if let Some(router) = self.router.as_mut() {
+            router.parsers.remove(name);
+        }
+
+        Ok(())
+    }
+
+    pub fn has_node(&self, name: &str) -> bool {
+        self.nodes.contains_key(name)
     }
 
     // Connect two nodes together bidirectionally
-    pub fn connect(&mut self, node1_name: &str, node2_name: &str) {
-        let node1 = self.nodes.get_mut(node1_name).unwrap();
-        node1.connect(node2_name);
+    pub fn connect(&mut self, node1_name: &str, node2_name: &str) -> Result<(), String> {
+        self.connect_one_way(node1_name, node2_name)?;
+        self.connect_one_way(node2_name, node1_name)
+    }
+
+    // Connect two nodes so that data only flows from one to the other
+    pub fn connect_one_way(&mut self, from_name: &str, to_name: &str) -> Result<(), String> {
+        self.check_link(from_name, to_name)?;
+
+        let from = self.nodes.get_mut(from_name).unwrap();
+        from.connect(to_name);
+
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, node1_name: &str, node2_name: &str) -> Result<(), String> {
+        self.disconnect_one_way(node1_name, node2_name)?;
+        self.disconnect_one_way(node2_name, node1_name)
+    }
+
+    // Stop data flowing from one node to the other, leaving the opposite direction alone
+    pub fn disconnect_one_way(&mut self, from_name: &str, to_name: &str) -> Result<(), String> {
+        self.check_link(from_name, to_name)?;
+
+        let from = self.nodes.get_mut(from_name).unwrap();
+        from.peers.retain(|n| n != &to_name);
+
+        Ok(())
+    }
 
-        let node2 = self.nodes.get_mut(node2_name).unwrap();
-        node2.connect(node1_name);
+    // Route avr_net frames between peers by address instead of passing every byte along
+    pub fn enable_routing(&mut self, framing: Framing, frame_timeout: Option<Duration>) {
+        self.router = Some(Router::new(framing, frame_timeout));
     }
 
-    pub fn disconnect(&mut self, node1_name: &str, node2_name: &str) {
-        let node1 = self.nodes.get_mut(node1_name).unwrap();
-        node1.peers.retain(|n| n != &node2_name);
+    // How many frames the router has discarded because they were malformed, corrupted or interrupted
+    pub fn frames_dropped(&self) -> u64 {
+        match &self.router {
+            Some(router) => router.parsers.values().map(|parser| parser.frames_dropped()).sum(),
+            None => 0,
+        }
+    }
+
+    pub fn set_address(&mut self, node_name: &str, address: u16) -> Result<(), String> {
+        if !self.has_node(node_name) {
+            return Err(format!("Unknown node: {}", node_name));
+        }
+
+        self.addresses.insert(node_name.to_string(), address);
+
+        Ok(())
+    }
+
+    // The addresses of the nodes a node sends to, in ascending order
+    pub fn peer_addresses(&self, node_name: &str) -> Vec<u16> {
+        let mut addresses: Vec<u16> = match self.nodes.get(node_name) {
+            Some(node) => node.peers.iter().filter_map(|peer| self.addresses.get(peer).copied()).collect(),
+            None => vec![],
+        };
+
+        addresses.sort();
+        addresses
+    }
+
+    pub fn create_bus(&mut self, name: &str) -> Result<(), String> {
+        if self.buses.contains_key(name) {
+            return Err(format!("Bus already exists: {}", name));
+        }
+
+        self.buses.insert(name.to_string(), Bus::new());
 
-        let node2 = self.nodes.get_mut(node2_name).unwrap();
-        node2.peers.retain(|n| n != &node1_name);
+        Ok(())
+    }
+
+    pub fn destroy_bus(&mut self, name: &str) -> Result<(), String> {
+        match self.buses.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(format!("Unknown bus: {}", name)),
+        }
+    }
+
+    pub fn has_bus(&self, name: &str) -> bool {
+        self.buses.contains_key(name)
+    }
+
+    pub fn attach(&mut self, bus_name: &str, node_name: &str) -> Result<(), String> {
+        if !self.has_node(node_name) {
+            return Err(format!("Unknown node: {}", node_name));
+        }
+
+        let bus = self.buses.get_mut(bus_name).ok_or(format!("Unknown bus: {}", bus_name))?;
+        bus.members.insert(node_name.to_string());
+
+        Ok(())
+    }
+
+    pub fn detach(&mut self, bus_name: &str, node_name: &str) -> Result<(), String> {
+        let bus = self.buses.get_mut(bus_name).ok_or(format!("Unknown bus: {}", bus_name))?;
+
+        if !bus.members.remove(node_name) {
+            return Err(format!("{} is not attached to {}", node_name, bus_name));
+        }
+
+        Ok(())
+    }
+
+    pub fn set_bus_baud(&mut self, bus_name: &str, baud: u32) -> Result<(), String> {
+        if baud == 0 {
+            return Err(format!("Baud of {} must be positive", bus_name));
+        }
+
+        let bus = self.buses.get_mut(bus_name).ok_or(format!("Unknown bus: {}", bus_name))?;
+        bus.byte_time = Bus::byte_time(baud);
+
+        Ok(())
+    }
+
+    // How many bytes on the bus have been corrupted by overlapping transmissions
+    pub fn bus_collisions(&self, bus_name: &str) -> Option<u64> {
+        self.buses.get(bus_name).map(|bus| bus.collisions)
+    }
+
+    // Impair the link carrying data from one node to another
+    pub fn impair(&mut self, from_name: &str, to_name: &str, impairment: Impairment) -> Result<(), String> {
+        self.check_link(from_name, to_name)?;
+
+        let key = (from_name.to_string(), to_name.to_string());
+        match self.links.get_mut(&key) {
+            Some(link) => link.set_impairment(impairment),
+            None => {
+                let link = Link::new(impairment, link_seed(self.seed, from_name, to_name));
+                self.links.insert(key, link);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_link(&self, node1_name: &str, node2_name: &str) -> Result<(), String> {
+        for name in [node1_name, node2_name] {
+            if !self.has_node(name) {
+                return Err(format!("Unknown node: {}", name));
+            }
+        }
+
+        if node1_name == node2_name {
+            return Err(format!("Cannot connect {} to itself", node1_name));
+        }
+
+        Ok(())
     }
 
     // Broadcast a message from a node to all of its peers
     pub fn broadcast_from(&mut self, node_name: &str, data: &Vec<u8>) {
+        let now = self.time;
+        let node = self.nodes.get_mut(node_name).unwrap();
+        node.outgoing.extend(data.iter().map(|b| (now, *b)));
+    }
+
+    // Like broadcast_from, but with when each byte was sent, so that buses can tell which
+    // transmissions overlap
+    pub fn broadcast_at(&mut self, node_name: &str, data: &[(Duration, u8)]) {
         let node = self.nodes.get_mut(node_name).unwrap();
-        node.outgoing.extend(data.iter());
+        node.outgoing.extend_from_slice(data);
     }
 
     pub fn deliver_messages(&mut self) {
@@ -91,17 +354,49 @@ impl<'a> Network<'a> {
             // TODO: how do I avoid the clones here?
             let node = self.nodes.get(node_name).unwrap();
             let peer_names = node.peers.clone();
-            let outgoing = node.outgoing.clone();
+            let outgoing: Vec<u8> = node.outgoing.iter().map(|(_, b)| *b).collect();
+
+            let now = self.time;
+            let frames = self.router.as_mut().map(|router| router.parse(node_name, &outgoing, now));
 
             for peer_name in peer_names {
+                let routed;
+                let data = match &frames {
+                    Some(frames) => {
+                        routed = self.frames_for(&peer_name, frames);
+                        &routed
+                    }
+                    None => &outgoing,
+                };
+
+                let key = (node_name.clone(), peer_name.clone());
+
+                if let Some(link) = self.links.get_mut(&key) {
+                    for b in data {
+                        link.send(self.time, *b);
+                    }
+                    continue;
+                }
+
                 let peer = self.nodes.get_mut(peer_name.as_str()).unwrap();
 
-                for b in &outgoing {
+                for b in data {
                     peer.receiver.receive(*b);
                 }
             }
         }
 
+        // Deliver whatever has made it across impaired links
+        for ((_, peer_name), link) in self.links.iter_mut() {
+            let peer = self.nodes.get_mut(peer_name.as_str()).unwrap();
+
+            while let Some(b) = link.receive(self.time) {
+                peer.receiver.receive(b);
+            }
+        }
+
+        self.deliver_bus_messages();
+
         // Clear buffers
         for node_name in &node_names {
             let node = self.nodes.get_mut(node_name).unwrap();
@@ -109,7 +404,292 @@ impl<'a> Network<'a> {
         }
     }
 
+    // Serialize the frames that should be delivered to a node
+    fn frames_for(&self, node_name: &str, frames: &[AvrNetMessage]) -> Vec<u8> {
+        let framing = self.router.as_ref().map_or(Framing::Legacy, |router| router.framing);
+        let address = self.addresses.get(node_name).copied();
+        let mut data = Vec::new();
+
+        for frame in frames {
+            if frame.address == BROADCAST_ADDRESS || Some(frame.address) == address {
+                data.extend(frame.encode(framing).unwrap());
+            }
+        }
+
+        data
+    }
+
+    // A byte occupies the bus for as long as its bits take at the bus's baud, and any byte another
+    // member starts sending during that time collides with it. Colliding bytes are combined like
+    // drivers fighting over a wire, with zeros dominating. Since a byte may still collide with one
+    // sent by a member that is behind, it's only put on the bus once the network's time has
+    // passed its end.
+    fn deliver_bus_messages(&mut self) {
+        for bus in self.buses.values_mut() {
+            for name in &bus.members {
+                let sent = &self.nodes.get(name).unwrap().outgoing;
+                bus.pending.extend(sent.iter().map(|(at, b)| (*at, name.clone(), *b)));
+            }
+
+            bus.pending.sort_by_key(|(at, _, _)| *at);
+
+            let mut bus_data = Vec::new();
+
+            while let Some((start, _, _)) = bus.pending.first() {
+                let end = *start + bus.byte_time;
+
+                if end > self.time {
+                    break;
+                }
+
+                // A member's own bytes follow each other rather than overlap
+                let mut senders = BTreeSet::new();
+                let mut combined = 0xff;
+
+                bus.pending.retain(|(at, sender, b)| {
+                    if *at < end && senders.insert(sender.clone()) {
+                        combined &= b;
+                        return false;
+                    }
+
+                    true
+                });
+
+                if senders.len() > 1 {
+                    bus.collisions += 1;
+                }
+
+                bus_data.push(combined);
+            }
+
+            // Everyone hears the bus, including their own transmissions
+            for name in &bus.members {
+                let member = self.nodes.get_mut(name).unwrap();
+
+                for b in &bus_data {
+                    member.receiver.receive(*b);
+                }
+            }
+        }
+    }
+
     pub fn node_names(&self) -> Vec<String> {
         self.nodes.keys().map(|s| s.clone()).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use crate::avr_net::Framing;
+    use crate::link::Impairment;
+    use crate::network::{Network, NetworkReceive};
+
+    struct RecordingReceiver {
+        received: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl NetworkReceive<'_> for RecordingReceiver {
+        fn receive(&mut self, b: u8) {
+            self.received.borrow_mut().push(b);
+        }
+    }
+
+    fn recording_node(network: &mut Network, name: &str) -> Rc<RefCell<Vec<u8>>> {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        network.create_node(name, RecordingReceiver { received: received.clone() });
+        received
+    }
+
+    #[test]
+    fn disconnect_stops_delivery() {
+        let mut network = Network::new();
+        let _a = recording_node(&mut network, "a");
+        let b = recording_node(&mut network, "b");
+
+        network.connect("a", "b").unwrap();
+        network.broadcast_from("a", &vec![1]);
+        network.deliver_messages();
+
+        network.disconnect("a", "b").unwrap();
+        network.broadcast_from("a", &vec![2]);
+        network.deliver_messages();
+
+        assert_eq!(*b.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn destroy_node_removes_it_from_peers() {
+        let mut network = Network::new();
+        let a = recording_node(&mut network, "a");
+        let _b = recording_node(&mut network, "b");
+
+        network.connect("a", "b").unwrap();
+        network.destroy_node("b").unwrap();
+
+        // Would panic if "a" still had "b" as a peer
+        network.broadcast_from("a", &vec![1]);
+        network.deliver_messages();
+
+        assert!(a.borrow().is_empty());
+        assert!(network.connect("a", "b").is_err());
+    }
+
+    #[test]
+    fn impaired_link_delivers_after_latency() {
+        let mut network = Network::new();
+        let _a = recording_node(&mut network, "a");
+        let b = recording_node(&mut network, "b");
+
+        network.connect("a", "b").unwrap();
+        network.impair("a", "b", Impairment { latency_us: 10, ..Default::default() }).unwrap();
+
+        network.broadcast_from("a", &vec![1]);
+        network.deliver_messages();
+        assert!(b.borrow().is_empty());
+
+        network.set_time(Duration::from_micros(10));
+        network.deliver_messages();
+        assert_eq!(*b.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn one_way_link_only_carries_one_direction() {
+        let mut network = Network::new();
+        let sensor = recording_node(&mut network, "sensor");
+        let logger = recording_node(&mut network, "logger");
+
+        network.connect_one_way("sensor", "logger").unwrap();
+        network.broadcast_from("sensor", &vec![1]);
+        network.broadcast_from("logger", &vec![2]);
+        network.deliver_messages();
+
+        assert_eq!(*logger.borrow(), vec![1]);
+        assert!(sensor.borrow().is_empty());
+    }
+
+    #[test]
+    fn disconnect_one_way_keeps_other_direction() {
+        let mut network = Network::new();
+        let a = recording_node(&mut network, "a");
+        let b = recording_node(&mut network, "b");
+
+        network.connect("a", "b").unwrap();
+        network.disconnect_one_way("a", "b").unwrap();
+        network.broadcast_from("a", &vec![1]);
+        network.broadcast_from("b", &vec![2]);
+        network.deliver_messages();
+
+        assert_eq!(*a.borrow(), vec![2]);
+        assert!(b.borrow().is_empty());
+    }
+
+    #[test]
+    fn bus_echoes_to_all_members() {
+        let mut network = Network::new();
+        let a = recording_node(&mut network, "a");
+        let b = recording_node(&mut network, "b");
+
+        network.create_bus("rs485").unwrap();
+        network.attach("rs485", "a").unwrap();
+        network.attach("rs485", "b").unwrap();
+
+        network.broadcast_from("a", &vec![1, 2]);
+        network.set_time(Duration::from_millis(10));
+        network.deliver_messages();
+
+        assert_eq!(*a.borrow(), vec![1, 2]);
+        assert_eq!(*b.borrow(), vec![1, 2]);
+        assert_eq!(network.bus_collisions("rs485"), Some(0));
+    }
+
+    #[test]
+    fn bus_corrupts_overlapping_transmissions() {
+        let mut network = Network::new();
+        let a = recording_node(&mut network, "a");
+        let _b = recording_node(&mut network, "b");
+
+        network.create_bus("rs485").unwrap();
+        network.attach("rs485", "a").unwrap();
+        network.attach("rs485", "b").unwrap();
+
+        // At 9600 baud a byte takes about 1.04 ms, so these overlap
+        network.broadcast_at("a", &[(Duration::ZERO, 0b1100), (Duration::from_micros(1100), 7)]);
+        network.broadcast_at("b", &[(Duration::from_micros(500), 0b1010)]);
+        network.set_time(Duration::from_millis(10));
+        network.deliver_messages();
+
+        assert_eq!(*a.borrow(), vec![0b1000, 7]);
+        assert_eq!(network.bus_collisions("rs485"), Some(1));
+    }
+
+    #[test]
+    fn bus_keeps_transmissions_apart_in_time() {
+        let mut network = Network::new();
+        let a = recording_node(&mut network, "a");
+        let _b = recording_node(&mut network, "b");
+
+        network.create_bus("rs485").unwrap();
+        network.attach("rs485", "a").unwrap();
+        network.attach("rs485", "b").unwrap();
+        network.set_bus_baud("rs485", 115_200).unwrap();
+
+        // Sent in the same round of delivery, but a byte takes under 0.1 ms at this baud
+        network.broadcast_at("a", &[(Duration::ZERO, 0b1100)]);
+        network.broadcast_at("b", &[(Duration::from_micros(500), 0b1010)]);
+        network.set_time(Duration::from_millis(10));
+        network.deliver_messages();
+
+        assert_eq!(*a.borrow(), vec![0b1100, 0b1010]);
+        assert_eq!(network.bus_collisions("rs485"), Some(0));
+    }
+
+    #[test]
+    fn bus_waits_for_members_that_are_behind() {
+        let mut network = Network::new();
+        let a = recording_node(&mut network, "a");
+        let _b = recording_node(&mut network, "b");
+
+        network.create_bus("rs485").unwrap();
+        network.attach("rs485", "a").unwrap();
+        network.attach("rs485", "b").unwrap();
+
+        // b may still send something overlapping until the network has caught up with a's byte
+        network.broadcast_at("a", &[(Duration::from_millis(1), 0b1100)]);
+        network.set_time(Duration::from_millis(1));
+        network.deliver_messages();
+        assert!(a.borrow().is_empty());
+
+        network.broadcast_at("b", &[(Duration::from_micros(1500), 0b1010)]);
+        network.set_time(Duration::from_millis(3));
+        network.deliver_messages();
+
+        assert_eq!(*a.borrow(), vec![0b1000]);
+        assert_eq!(network.bus_collisions("rs485"), Some(1));
+    }
+
+    #[test]
+    fn router_delivers_by_address() {
+        let mut network = Network::new();
+        let _main = recording_node(&mut network, "main");
+        let cam = recording_node(&mut network, "cam");
+        let thrust = recording_node(&mut network, "thrust");
+
+        network.enable_routing(Framing::Legacy, None);
+        network.set_address("cam", 2).unwrap();
+        network.set_address("thrust", 3).unwrap();
+        network.connect("main", "cam").unwrap();
+        network.connect("main", "thrust").unwrap();
+
+        // A frame for the camera, split across deliveries, then a broadcast
+        network.broadcast_from("main", &vec![0, 2, 0, 1]);
+        network.deliver_messages();
+        network.broadcast_from("main", &vec![9, 0xff, 0xff, 0, 1, 5]);
+        network.deliver_messages();
+
+        assert_eq!(*cam.borrow(), vec![0, 2, 0, 1, 9, 0xff, 0xff, 0, 1, 5]);
+        assert_eq!(*thrust.borrow(), vec![0xff, 0xff, 0, 1, 5]);
+    }
+}
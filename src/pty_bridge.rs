@@ -0,0 +1,133 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use nix::fcntl::OFlag;
+use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+
+// A pseudo-terminal on the host that stands in for the other end of one of a device's UARTs, so
+// that serial tools can be pointed at the simulated device
+pub struct PtyBridge {
+    uart: char,
+    master: PtyMaster,
+    // Held open so that the terminal stays usable while no tool has it open
+    _slave: File,
+    path: String,
+    link: Option<PathBuf>,
+}
+
+impl PtyBridge {
+    pub fn open(uart: char, link: Option<&str>) -> Result<Self, io::Error> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)?;
+        grantpt(&master)?;
+        unlockpt(&master)?;
+        let path = ptsname_r(&master)?;
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        // Bytes pass through untouched, without echo or line editing
+        let mut termios = tcgetattr(&slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&slave, SetArg::TCSANOW, &termios)?;
+
+        let link = match link {
+            Some(link) => {
+                let link = PathBuf::from(link);
+
+                // Replace a link left behind by an earlier run, but nothing else
+                if link.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                    std::fs::remove_file(&link)?;
+                }
+
+                std::os::unix::fs::symlink(&path, &link)?;
+                Some(link)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            uart,
+            master,
+            _slave: slave,
+            path,
+            link,
+        })
+    }
+
+    pub fn uart(&self) -> char {
+        self.uart
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Send data from the device to the host. Like a real serial port, what nobody reads is lost
+    // once the terminal's buffer is full.
+    pub fn write(&mut self, data: &[u8]) {
+        let _ = self.master.write(data);
+    }
+
+    // Take what the host has sent to the device
+    pub fn read(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 256];
+
+        while let Ok(len) = self.master.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+
+            data.extend(&buf[..len]);
+        }
+
+        data
+    }
+}
+
+impl Drop for PtyBridge {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use crate::pty_bridge::PtyBridge;
+
+    #[test]
+    fn bytes_pass_through_unchanged() {
+        let mut bridge = PtyBridge::open('0', None).unwrap();
+        let mut host = OpenOptions::new().read(true).write(true).open(bridge.path()).unwrap();
+
+        // Raw mode, so no newline translation or echo
+        host.write_all(b"hi\n").unwrap();
+
+        // The terminal hands data over in the background
+        let mut received = vec![];
+        for _ in 0..100 {
+            received.extend(bridge.read());
+            if received.len() >= 3 {
+                break;
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert_eq!(received, b"hi\n");
+
+        bridge.write(b"\r\x00");
+        let mut buf = [0; 2];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\r\x00");
+    }
+}
@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::avr_simulator::AvrSimulator;
+
+// How long a wait may take, in simulated time, unless the scenario says otherwise
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+// Received data kept per node while waiting for a pattern, so a chatty node can't eat up memory
+const MAX_RECEIVED: usize = 64 * 1024;
+
+// Stimulus and assertions run against a network in simulated time, e.g.
+//
+//   config: network.yaml
+//   steps:
+//     - action: wait_uart
+//       node: main
+//       pattern: READY
+//     - action: set_pin
+//       node: main
+//       port: B
+//       pin: 0
+//       state: true
+//     - action: wait
+//       ms: 10
+//     - action: assert_pin
+//       node: main
+//       port: D
+//       pin: 7
+//       state: true
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    // Network to run, relative to the scenario file
+    pub config: String,
+
+    // Overrides for the network config, like `--set`
+    #[serde(default = "Vec::new")]
+    pub set: Vec<String>,
+
+    // Default for how long waits may take, in milliseconds of simulated time
+    pub timeout_ms: Option<u64>,
+
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    // Wait until a node has sent `pattern` on the network since the last wait on it
+    WaitUart { node: String, pattern: String, timeout_ms: Option<u64> },
+    // Write bytes to a node's UART
+    SendUart {
+        node: String,
+        data: String,
+        #[serde(default = "default_uart")]
+        uart: char,
+    },
+    SetPin { node: String, port: char, pin: u8, state: bool },
+    SetAnalog { node: String, pin: u8, millivolts: u32 },
+    AssertPin { node: String, port: char, pin: u8, state: bool },
+    // Let the network run for a while
+    Wait { ms: u64 },
+}
+
+fn default_uart() -> char {
+    '0'
+}
+
+pub fn load(scenario_file_path_str: &str) -> Result<Scenario, io::Error> {
+    let scenario_file_path = Path::new(scenario_file_path_str);
+
+    let yaml = std::fs::read_to_string(scenario_file_path)?;
+    let mut scenario: Scenario = serde_yaml::from_str(&yaml)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    scenario.config = scenario_file_path.parent().unwrap().join(&scenario.config).to_string_lossy().into_owned();
+
+    Ok(scenario)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Running,
+    Passed,
+    Failed(String),
+}
+
+// Works through the steps of a scenario as the network runs, printing a timeline on the way
+pub struct ScenarioRunner {
+    steps: Vec<Step>,
+    default_timeout: Duration,
+    current: usize,
+    step_started: Option<Duration>,
+    received: HashMap<String, Vec<u8>>,
+}
+
+impl ScenarioRunner {
+    pub fn new(scenario: Scenario) -> Self {
+        Self {
+            steps: scenario.steps,
+            default_timeout: Duration::from_millis(scenario.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+            current: 0,
+            step_started: None,
+            received: HashMap::new(),
+        }
+    }
+
+    // Data a node has sent on the network
+    pub fn receive(&mut self, node: &str, data: &[u8]) {
+        let received = self.received.entry(node.to_string()).or_default();
+        received.extend_from_slice(data);
+
+        if received.len() > MAX_RECEIVED {
+            received.drain(..received.len() - MAX_RECEIVED);
+        }
+    }
+
+    // Carry out as many steps as possible at simulated time `now`
+    pub fn poll(&mut self, now: Duration, devs: &HashMap<String, Rc<RefCell<AvrSimulator>>>) -> Outcome {
+        while let Some(step) = self.steps.get(self.current).cloned() {
+            let started = match self.step_started {
+                Some(started) => started,
+                None => {
+                    self.step_started = Some(now);
+                    now
+                }
+            };
+
+            let done = match self.run_step(&step, now, started, devs) {
+                Ok(done) => done,
+                Err(reason) => {
+                    log(now, &format!("FAILED: {}", reason));
+                    return Outcome::Failed(reason);
+                }
+            };
+
+            if !done {
+                return Outcome::Running;
+            }
+
+            self.current += 1;
+            self.step_started = None;
+        }
+
+        Outcome::Passed
+    }
+
+    // Whether the step is done, or why it failed
+    fn run_step(&mut self, step: &Step, now: Duration, started: Duration, devs: &HashMap<String, Rc<RefCell<AvrSimulator>>>) -> Result<bool, String> {
+        let device = |node: &String| devs.get(node).ok_or(format!("unknown device: {}", node));
+
+        // simavr would panic about a pin the device doesn't have
+        let device_with_pin = |node: &String, port: char, pin: u8| {
+            let dev = device(node)?;
+
+            if !dev.borrow().has_pin(port, pin) {
+                return Err(format!("{} has no pin P{}{}", node, port, pin));
+            }
+
+            Ok(dev)
+        };
+
+        match step {
+            Step::WaitUart { node, pattern, timeout_ms } => {
+                let received = self.received.entry(node.clone()).or_default();
+                let position = received.windows(pattern.len().max(1)).position(|window| window == pattern.as_bytes());
+
+                if let Some(position) = position {
+                    received.drain(..position + pattern.len());
+                    log(now, &format!("{} sent {:?}", node, pattern));
+                    return Ok(true);
+                }
+
+                let timeout = timeout_ms.map(Duration::from_millis).unwrap_or(self.default_timeout);
+
+                if now - started > timeout {
+                    return Err(format!("{} didn't send {:?} within {} ms", node, pattern, timeout.as_millis()));
+                }
+
+                Ok(false)
+            }
+            Step::SendUart { node, data, uart } => {
+                let dev = &mut *device(node)?.borrow_mut();
+
+                if !dev.has_uart(*uart) {
+                    return Err(format!("{} has no UART{}", node, uart));
+                }
+
+                for b in data.bytes() {
+                    dev.write_uart(*uart, b);
+                }
+
+                log(now, &format!("sent {:?} to {}", data, node));
+                Ok(true)
+            }
+            Step::SetPin { node, port, pin, state } => {
+                device_with_pin(node, *port, *pin)?.borrow_mut().set_digital_pin(*port, *pin, *state);
+                log(now, &format!("set {} P{}{} {}", node, port, pin, level(*state)));
+                Ok(true)
+            }
+            Step::SetAnalog { node, pin, millivolts } => {
+                let dev = device(node)?;
+
+                if !dev.borrow().has_adc() {
+                    return Err(format!("{} has no ADC", node));
+                }
+
+                dev.borrow_mut().set_analog_pin(*pin, *millivolts);
+                log(now, &format!("set {} ADC{} to {} mV", node, pin, millivolts));
+                Ok(true)
+            }
+            Step::AssertPin { node, port, pin, state } => {
+                let actual = device_with_pin(node, *port, *pin)?.borrow_mut().get_digital_pin(*port, *pin);
+
+                if actual != *state {
+                    return Err(format!("{} P{}{} is {}, expected {}", node, port, pin, level(actual), level(*state)));
+                }
+
+                log(now, &format!("{} P{}{} is {}", node, port, pin, level(actual)));
+                Ok(true)
+            }
+            Step::Wait { ms } => Ok(now - started >= Duration::from_millis(*ms)),
+        }
+    }
+}
+
+fn level(state: bool) -> &'static str {
+    match state {
+        true => "high",
+        false => "low",
+    }
+}
+
+fn log(now: Duration, text: &str) {
+    println!("{:>12.3} ms  {}", now.as_secs_f64() * 1000.0, text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runner(yaml: &str) -> ScenarioRunner {
+        ScenarioRunner::new(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn waits_for_patterns_in_simulated_time() {
+        let mut runner = runner("
+config: network.yaml
+timeout_ms: 100
+steps:
+  - action: wait_uart
+    node: main
+    pattern: READY
+  - action: wait
+    ms: 10
+");
+        let devs = HashMap::new();
+
+        assert_eq!(runner.poll(Duration::from_millis(0), &devs), Outcome::Running);
+
+        runner.receive("main", b"boot... REA");
+        assert_eq!(runner.poll(Duration::from_millis(5), &devs), Outcome::Running);
+
+        runner.receive("main", b"DY\n");
+        assert_eq!(runner.poll(Duration::from_millis(6), &devs), Outcome::Running);
+        assert_eq!(runner.poll(Duration::from_millis(15), &devs), Outcome::Running);
+        assert_eq!(runner.poll(Duration::from_millis(16), &devs), Outcome::Passed);
+    }
+
+    #[test]
+    fn waits_time_out() {
+        let mut runner = runner("
+config: network.yaml
+steps:
+  - action: wait_uart
+    node: main
+    pattern: READY
+    timeout_ms: 50
+");
+        let devs = HashMap::new();
+
+        assert_eq!(runner.poll(Duration::from_millis(10), &devs), Outcome::Running);
+        assert!(matches!(runner.poll(Duration::from_millis(61), &devs), Outcome::Failed(_)));
+    }
+
+    #[test]
+    fn unknown_steps_are_rejected() {
+        assert!(serde_yaml::from_str::<Scenario>("config: a.yaml\nsteps:\n  - action: jump\n").is_err());
+        assert!(serde_yaml::from_str::<Scenario>("config: a.yaml\nsteps:\n  - action: wait\n    ms: 1\n    s: 2\n").is_err());
+    }
+}
@@ -1,84 +1,25 @@
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, mpsc, Mutex};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
-use std::{mem, thread};
+use std::time::{Duration, Instant};
 
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::socket::setsockopt;
 use nix::sys::socket::sockopt::ReusePort;
 
-type ClientMap = Arc<Mutex<HashMap<u16, Client>>>;
+// Reading from a client stops while this much of its data is waiting to be taken, so that TCP
+// flow control slows down clients that send faster than the network consumes
+const MAX_RX_BUFFER: usize = 64 * 1024;
+// Data for a client that isn't reading is refused beyond this
+const MAX_TX_BUFFER: usize = 256 * 1024;
+// How long a client may take to receive what was queued for it when the server stops
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
-fn handle_client(mut stream: TcpStream, client_id: u16, clients: ClientMap) {
-    let (request_tx, request_rx) = mpsc::channel::<Vec<u8>>();
-
-    let mut stream_for_rx = stream;//Arc::new(Mutex::new(stream));
-    let mut stream_for_tx = stream_for_rx.try_clone().unwrap();
-
-    thread::spawn(move || {
-        let mut buf = [0; 1024];
-
-        loop {
-            let len = match stream_for_rx.read(&mut buf) {
-                Ok(len) => Some(len),
-                Err(_) => {
-                    println!("Cannot read data, client {} disconnected", client_id);
-                    break;
-                }
-            }.unwrap();
-
-            if len == 0 {
-                break;
-            }
-
-            request_tx.send(buf[..len].to_vec()).unwrap();
-        }
-    });
-
-    clients.lock().unwrap().insert(client_id, Client::new());
-
-    loop {
-        match request_rx.try_recv() {
-            Ok(buf) => {
-                let mut locked_clients = clients.lock().unwrap();
-                let client = locked_clients.get_mut(&client_id).unwrap();
-                client.rx_buffer.extend_from_slice(&buf);
-            }
-            Err(TryRecvError::Empty) => {},
-            Err(TryRecvError::Disconnected) => {
-                clients.lock().unwrap().remove(&client_id);
-                println!("Client {} disconnected", client_id);
-                break;
-            },
-        }
-
-        let mut locked_clients = clients.lock().unwrap();
-        // let client = locked_clients.get_mut(&client_id).unwrap();
-
-        match locked_clients.get_mut(&client_id) {
-            Some(client) => {
-                let buffer = &mut client.tx_buffer;
-
-                if buffer.len() > 0 {
-                    match stream_for_tx.write(&buffer) {
-                        Ok(bytes_written) => {
-                            buffer.drain(0..bytes_written);
-                        }
-                        Err(_) => {
-                            println!("Cannot write data, client {} disconnected", client_id);
-                        }
-                    }
-                }
-            }
-            None => {
-                println!("Client {} disconnected", client_id);
-                break;
-            }
-        }
-    }
-}
+pub type ClientId = u64;
 
 struct Client {
     rx_buffer: Vec<u8>,
@@ -94,99 +35,430 @@ impl Client {
     }
 }
 
+// The socket of a client, which only the server thread touches
+struct Connection {
+    stream: TcpStream,
+    last_activity: Instant,
+}
+
+// What the server thread takes over when it starts
+struct Endpoints {
+    listener: TcpListener,
+    wake_rx: UnixStream,
+}
+
 pub struct ServerNode {
-    clients: ClientMap,
-    address: String,
-    shutdown_flag: Arc<AtomicBool>,
+    clients: Mutex<HashMap<ClientId, Client>>,
+    endpoints: Mutex<Option<Endpoints>>,
+    // Written to in order to wake the server thread when there is something for it to do
+    wake_tx: UnixStream,
+    idle_timeout: Option<Duration>,
+    shutdown_flag: AtomicBool,
 }
 
 impl ServerNode {
-    pub fn new(address: &str) -> Self {
-        Self {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            address: address.to_owned(),
-            shutdown_flag: Arc::new(AtomicBool::new(false)),
-        }
+    pub fn new(address: &str) -> Result<Self, io::Error> {
+        let listener = TcpListener::bind(address)?;
+        setsockopt(&listener, ReusePort, &true)?;
+        listener.set_nonblocking(true)?;
+
+        let (wake_tx, wake_rx) = UnixStream::pair()?;
+        wake_tx.set_nonblocking(true)?;
+        wake_rx.set_nonblocking(true)?;
+
+        Ok(Self {
+            clients: Mutex::new(HashMap::new()),
+            endpoints: Mutex::new(Some(Endpoints { listener, wake_rx })),
+            wake_tx,
+            idle_timeout: None,
+            shutdown_flag: AtomicBool::new(false),
+        })
+    }
+
+    // Disconnect clients that neither send nor receive anything for this long
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
     }
 
+    // Serve clients until shut down. Everything happens on the calling thread, which sleeps until
+    // a socket is ready or it is woken up.
     pub fn start(&self) {
-        let listener = TcpListener::bind(&self.address).unwrap();
-        setsockopt(&listener, ReusePort, &true).unwrap();
+        let Endpoints { listener, wake_rx } = self.endpoints.lock().unwrap().take()
+            .expect("Server already started");
+
+        let mut connections: HashMap<ClientId, Connection> = HashMap::new();
+        let mut next_client_id: ClientId = 0;
+
+        while !self.shutdown_flag.load(Ordering::Relaxed) {
+            // Clients may have been disconnected through the API since the last round
+            {
+                let clients = self.clients.lock().unwrap();
+                connections.retain(|id, _| clients.contains_key(id));
+            }
 
-        let mut next_client_id: u16 = 0;
+            let client_ids: Vec<ClientId> = connections.keys().copied().collect();
+            let client_flags: Vec<PollFlags> = {
+                let clients = self.clients.lock().unwrap();
+                client_ids.iter().map(|id| {
+                    let mut flags = PollFlags::empty();
 
-        for stream in listener.incoming() {
-            if self.shutdown_flag.load(Ordering::Relaxed) {
-                break;
+                    // Gone already, which the read below notices
+                    let client = match clients.get(id) {
+                        Some(client) => client,
+                        None => return flags,
+                    };
+
+                    if client.rx_buffer.len() < MAX_RX_BUFFER {
+                        flags |= PollFlags::POLLIN;
+                    }
+
+                    if !client.tx_buffer.is_empty() {
+                        flags |= PollFlags::POLLOUT;
+                    }
+
+                    flags
+                }).collect()
+            };
+
+            let mut fds = vec![
+                PollFd::new(&listener, PollFlags::POLLIN),
+                PollFd::new(&wake_rx, PollFlags::POLLIN),
+            ];
+
+            for (id, flags) in client_ids.iter().zip(&client_flags) {
+                fds.push(PollFd::new(&connections[id].stream, *flags));
             }
 
-            match stream {
-                Ok(stream) => {
-                    let client_data = self.clients.clone();
+            match poll(&mut fds, self.poll_timeout(&connections)) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(err) => {
+                    println!("Cannot poll sockets: {}", err);
+                    break;
+                }
+            }
+
+            let revents: Vec<PollFlags> = fds.iter()
+                .map(|fd| fd.revents().unwrap_or(PollFlags::empty()))
+                .collect();
+            drop(fds);
+
+            if !revents[1].is_empty() {
+                let mut buf = [0; 64];
+                while let Ok(len) = (&wake_rx).read(&mut buf) {
+                    if len == 0 {
+                        break;
+                    }
+                }
+            }
+
+            if revents[0].contains(PollFlags::POLLIN) {
+                while let Ok((stream, _)) = listener.accept() {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+
                     let client_id = next_client_id;
                     next_client_id += 1;
 
-                    thread::spawn(move || handle_client(stream, client_id, client_data));
+                    self.clients.lock().unwrap().insert(client_id, Client::new());
+                    connections.insert(client_id, Connection { stream, last_activity: Instant::now() });
                 }
-                Err(_) => {
-                    // Handle error
+            }
+
+            for (id, events) in client_ids.iter().zip(&revents[2..]) {
+                let connection = connections.get_mut(id).unwrap();
+
+                let open = self.read_from(*id, connection, *events)
+                    && self.write_to(*id, connection, *events);
+
+                if !open {
+                    connections.remove(id);
+                    self.clients.lock().unwrap().remove(id);
+                    println!("Client {} disconnected", id);
+                }
+            }
+
+            if let Some(idle_timeout) = self.idle_timeout {
+                let idle_ids: Vec<ClientId> = connections.iter()
+                    .filter(|(_, connection)| connection.last_activity.elapsed() >= idle_timeout)
+                    .map(|(id, _)| *id)
+                    .collect();
+
+                for id in idle_ids {
+                    connections.remove(&id);
+                    self.clients.lock().unwrap().remove(&id);
+                    println!("Client {} timed out", id);
                 }
             }
         }
 
+        self.close_all(connections);
+
         println!("Server stopped");
     }
 
+    // Send what is still queued for each client and then close the connection
+    fn close_all(&self, connections: HashMap<ClientId, Connection>) {
+        let mut clients = self.clients.lock().unwrap();
+
+        for (id, connection) in connections {
+            if let Some(client) = clients.get(&id) {
+                let mut stream = connection.stream;
+                let _ = stream.set_nonblocking(false);
+                let _ = stream.set_write_timeout(Some(CLOSE_TIMEOUT));
+                let _ = stream.write_all(&client.tx_buffer);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        clients.clear();
+    }
+
+    // Sleep until the next client would time out, or indefinitely
+    fn poll_timeout(&self, connections: &HashMap<ClientId, Connection>) -> libc::c_int {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return -1,
+        };
+
+        connections.values()
+            .map(|connection| idle_timeout.saturating_sub(connection.last_activity.elapsed()))
+            .min()
+            // Round up so that the client has timed out by the time poll returns
+            .map_or(-1, |remaining| (remaining.as_millis() + 1).min(libc::c_int::MAX as u128) as libc::c_int)
+    }
+
+    // Returns false once the client is gone
+    fn read_from(&self, client_id: ClientId, connection: &mut Connection, events: PollFlags) -> bool {
+        if !events.intersects(PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR) {
+            return true;
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let client = match clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => return false,
+        };
+
+        let mut buf = [0; 4096];
+
+        while client.rx_buffer.len() < MAX_RX_BUFFER {
+            let space = (MAX_RX_BUFFER - client.rx_buffer.len()).min(buf.len());
+
+            match connection.stream.read(&mut buf[..space]) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    client.rx_buffer.extend_from_slice(&buf[..len]);
+                    connection.last_activity = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+
+        // The buffer is full, so the hangup can't be read until the data has been taken
+        !events.intersects(PollFlags::POLLHUP | PollFlags::POLLERR)
+    }
+
+    fn write_to(&self, client_id: ClientId, connection: &mut Connection, events: PollFlags) -> bool {
+        if !events.contains(PollFlags::POLLOUT) {
+            return true;
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let client = match clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => return false,
+        };
+
+        while !client.tx_buffer.is_empty() {
+            match connection.stream.write(&client.tx_buffer) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    client.tx_buffer.drain(0..len);
+                    connection.last_activity = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    println!("Cannot write data, client {} disconnected", client_id);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn wake(&self) {
+        // A full pipe already means the server thread will wake up
+        let _ = (&self.wake_tx).write(&[0]);
+    }
+
     pub fn shutdown(&self) {
         self.shutdown_flag.store(true, Ordering::Relaxed);
-        let _ = TcpStream::connect(&self.address);
+        self.wake();
     }
 
-    pub fn connected_client_ids(&self) -> Vec<u16> {
+    pub fn connected_client_ids(&self) -> Vec<ClientId> {
         let data = self.clients.lock().unwrap();
         data.keys().cloned().collect()
     }
 
-    pub fn read_data(&self, client_id: u16) -> Option<Vec<u8>> {
-        match self.clients.lock() {
-            Ok(mut client_map) => {
-                match client_map.get_mut(&client_id) {
-                    Some(client) => {
-                        let buffer = &mut client.rx_buffer;
+    pub fn read_data(&self, client_id: ClientId) -> Option<Vec<u8>> {
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.get_mut(&client_id)?;
 
-                        if buffer.len() == 0 {
-                            return None;
-                        }
+        if client.rx_buffer.is_empty() {
+            return None;
+        }
 
-                        let data = buffer.clone();
-                        buffer.clear();
-                        println!("Read data from client {}: {:?}", client_id, data);
-                        return Some(data);
-                    }
-                    None => None
-                }
-            }
-            Err(_) => None
+        // The server thread stops reading from a client whose buffer is full
+        let was_full = client.rx_buffer.len() >= MAX_RX_BUFFER;
+        let data = std::mem::take(&mut client.rx_buffer);
+        drop(clients);
+
+        if was_full {
+            self.wake();
         }
+
+        Some(data)
     }
 
-    pub fn send_data(&self, client_id: u16, data: &Vec<u8>) {
-        if let Some(client) = self.clients.lock().unwrap().get_mut(&client_id) {
-            let buffer = &mut client.tx_buffer;
-            buffer.extend(data);
+    // Queue data for a client. Returns false if the data was refused, because the client is gone
+    // or hasn't kept up with what was already sent to it.
+    pub fn send_data(&self, client_id: ClientId, data: &[u8]) -> bool {
+        {
+            let mut clients = self.clients.lock().unwrap();
+            let client = match clients.get_mut(&client_id) {
+                Some(client) => client,
+                None => return false,
+            };
+
+            if client.tx_buffer.len() + data.len() > MAX_TX_BUFFER {
+                return false;
+            }
+
+            client.tx_buffer.extend_from_slice(data);
         }
+
+        self.wake();
+
+        true
     }
 
-    pub fn is_connected(&self, client_id: u16) -> bool {
+    pub fn is_connected(&self, client_id: ClientId) -> bool {
         let data = self.clients.lock().unwrap();
         data.contains_key(&client_id)
     }
 
-    pub fn disconnect(&self, client_id: u16) {
+    pub fn disconnect(&self, client_id: ClientId) {
         self.clients.lock().unwrap().remove(&client_id);
+        self.wake();
     }
 
     pub fn disconnect_all(&self) {
         self.clients.lock().unwrap().clear();
+        self.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::server_node::ServerNode;
+
+    fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+        for _ in 0..200 {
+            if condition() {
+                return true;
+            }
+
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        false
+    }
+
+    fn start_server(idle_timeout: Option<Duration>) -> (Arc<ServerNode>, u16) {
+        let server = ServerNode::new("127.0.0.1:0").unwrap().with_idle_timeout(idle_timeout);
+        let port = server.endpoints.lock().unwrap().as_ref().unwrap().listener.local_addr().unwrap().port();
+        let server = Arc::new(server);
+
+        {
+            let server = server.clone();
+            thread::spawn(move || server.start());
+        }
+
+        (server, port)
+    }
+
+    #[test]
+    fn echoes_through_the_buffers() {
+        let (server, port) = start_server(None);
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"hello").unwrap();
+
+        let mut received = None;
+        assert!(wait_for(|| {
+            received = server.connected_client_ids().first().and_then(|id| server.read_data(*id));
+            received.is_some()
+        }));
+        assert_eq!(received.unwrap(), b"hello");
+
+        let id = server.connected_client_ids()[0];
+        assert!(server.send_data(id, b"world"));
+
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn client_ids_are_unique() {
+        let (server, port) = start_server(None);
+
+        let first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(wait_for(|| server.connected_client_ids() == vec![0]));
+        drop(first);
+        assert!(wait_for(|| server.connected_client_ids().is_empty()));
+
+        let _second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(wait_for(|| server.connected_client_ids() == vec![1]));
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn shutdown_sends_what_is_queued() {
+        let (server, port) = start_server(None);
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(wait_for(|| server.connected_client_ids().len() == 1));
+
+        server.send_data(server.connected_client_ids()[0], b"bye");
+        server.shutdown();
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[test]
+    fn idle_clients_time_out() {
+        let (server, port) = start_server(Some(Duration::from_millis(50)));
+
+        let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(wait_for(|| server.connected_client_ids().len() == 1));
+        assert!(wait_for(|| server.connected_client_ids().is_empty()));
+
+        server.shutdown();
     }
 }
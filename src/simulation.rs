@@ -0,0 +1,604 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+use crate::avr_simulator::{AvrDuration, AvrSimulator, AvrState, PinChange, ResetKind, Waveform};
+use crate::config::{self, MycochipConfig};
+use crate::network::{Network, NetworkReceive};
+
+pub type AvrSimulatorRef = Rc<RefCell<AvrSimulator>>;
+
+// Instructions each device runs between two exchanges of data over the network
+const STEPS_PER_ROUND: usize = 1000;
+
+pub struct AvrReceiver {
+    pub avr: AvrSimulatorRef,
+}
+
+impl<'a> NetworkReceive<'a> for AvrReceiver {
+    fn receive(&mut self, b: u8) {
+        self.avr.borrow_mut().write_uart('0', b);
+    }
+}
+
+// Stands between a device's UART and the wire, like an RS-485 transceiver, so that output only
+// gets through while the driver enable pin is high
+pub struct Transceiver {
+    driver_enable: config::PinConfig,
+    // Level of the driver enable pin as of the last update
+    enabled: bool,
+    driven: Vec<(Duration, u8)>,
+}
+
+impl Transceiver {
+    pub fn new(driver_enable: config::PinConfig, avr: &mut AvrSimulator) -> Result<Self, String> {
+        if !avr.has_pin(driver_enable.port, driver_enable.pin) {
+            return Err(format!("There is no pin P{}{} to enable the driver", driver_enable.port, driver_enable.pin));
+        }
+
+        Ok(Self {
+            driver_enable,
+            enabled: avr.get_digital_pin(driver_enable.port, driver_enable.pin),
+            driven: Vec::new(),
+        })
+    }
+
+    // Called with the pin changes since the last update, so that each byte is gated by the level
+    // the pin had at the time the byte was sent
+    pub fn update(&mut self, avr: &mut AvrSimulator, pin_changes: &[PinChange]) {
+        let mut changes = pin_changes.iter()
+            .filter(|change| change.port == self.driver_enable.port && change.pin == self.driver_enable.pin)
+            .peekable();
+
+        while let Some((at, b)) = avr.read_uart_timed('0') {
+            while let Some(change) = changes.next_if(|change| change.at <= at) {
+                self.enabled = change.high;
+            }
+
+            if self.enabled {
+                self.driven.push((to_duration(at), b));
+            }
+        }
+
+        if let Some(change) = changes.last() {
+            self.enabled = change.high;
+        }
+    }
+
+    pub fn take_driven(&mut self) -> Vec<(Duration, u8)> {
+        std::mem::take(&mut self.driven)
+    }
+}
+
+pub fn new_avr(device_name: &str, device: &config::AvrDevice) -> Result<AvrSimulator, String> {
+    // Devices without a clock of their own run as fast as possible
+    let mut builder = AvrSimulator::builder(&device.mcu)
+        .frequency(device.frequency.unwrap_or(u32::MAX))
+        .firmware(&device.firmware);
+
+    if let Some(eeprom) = &device.eeprom {
+        builder = builder.eeprom(eeprom);
+    }
+
+    builder.build().map_err(|e| format!("Cannot start {}: {}", device_name, e))
+}
+
+pub fn connect_device(network: &mut Network, device_name: &str, device: &config::Device) -> Result<(), String> {
+    for peer_name in device.peers() {
+        network.connect(device_name, peer_name)?;
+    }
+
+    for source_name in device.listens_to() {
+        network.connect_one_way(source_name, device_name)?;
+    }
+
+    for bus_name in device.buses() {
+        if !network.has_bus(bus_name) {
+            network.create_bus(bus_name)?;
+        }
+
+        network.attach(bus_name, device_name)?;
+    }
+
+    Ok(())
+}
+
+// A reset line reads a pin of another device, which must have that pin. The line is checked by
+// whichever of the two devices is created second.
+pub fn check_reset_lines(
+    device_name: &str,
+    avr: &AvrSimulator,
+    device: &config::AvrDevice,
+    devs: &HashMap<String, AvrSimulatorRef>,
+    avr_configs: &HashMap<String, config::AvrDevice>,
+) -> Result<(), String> {
+    let check = |reset_line: &config::ResetLine, source: &AvrSimulator| {
+        match source.has_pin(reset_line.port, reset_line.pin) {
+            true => Ok(()),
+            false => Err(format!("{} has no pin P{}{} for a reset line", reset_line.node, reset_line.port, reset_line.pin)),
+        }
+    };
+
+    if let Some(reset_line) = &device.reset_line {
+        if let Some(source) = devs.get(&reset_line.node) {
+            check(reset_line, &source.borrow())?;
+        }
+    }
+
+    for reset_line in avr_configs.values().filter_map(|other| other.reset_line.as_ref()) {
+        if reset_line.node == device_name {
+            check(reset_line, avr)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Buses are created as devices mention them, so their settings are applied once all are connected
+pub fn configure_buses(network: &mut Network, config: &MycochipConfig) -> Result<(), String> {
+    for (bus_name, bus) in &config.buses {
+        network.set_bus_baud(bus_name, bus.baud)?;
+    }
+
+    Ok(())
+}
+
+pub fn impair_configured_link(network: &mut Network, link: &config::LinkConfig) -> Result<(), String> {
+    let [node1_name, node2_name] = &link.between;
+
+    network.impair(node1_name, node2_name, link.impairment.clone())
+        .and_then(|_| network.impair(node2_name, node1_name, link.impairment.clone()))
+}
+
+// Drop gateways from a config, along with links to them, since they would have to listen
+pub fn leave_out_gateways(config: &mut MycochipConfig) {
+    let gateway_names: HashSet<String> = config.devices.iter()
+        .filter(|(_, device)| !matches!(device, config::Device::Avr(_)))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    for gateway_name in &gateway_names {
+        println!("Leaving out gateway {} in a headless run", gateway_name);
+        config.devices.remove(gateway_name);
+    }
+
+    for device in config.devices.values_mut() {
+        if let config::Device::Avr(avr) = device {
+            avr.peers.retain(|peer_name| !gateway_names.contains(peer_name));
+            avr.listens_to.retain(|source_name| !gateway_names.contains(source_name));
+        }
+    }
+
+    config.links.retain(|link| !link.between.iter().any(|node_name| gateway_names.contains(node_name)));
+    config.websocket = None;
+}
+
+pub fn sim_time_of(dev: &AvrSimulatorRef) -> Duration {
+    to_duration(dev.borrow().elapsed())
+}
+
+pub fn to_duration(duration: AvrDuration) -> Duration {
+    Duration::from_secs_f64(duration.as_secs_f64())
+}
+
+// A device in one of these states will not run again unless it is restarted
+pub fn is_stopped(state: AvrState) -> bool {
+    state == AvrState::Crashed || state == AvrState::Done
+}
+
+// Runs every device for a batch of instructions, leaving out stopped ones and those held in reset.
+// Returns the pins that changed on each device during the batch.
+pub fn run_batch(
+    devs: &HashMap<String, AvrSimulatorRef>,
+    transceivers: &mut HashMap<String, Transceiver>,
+    held: &HashSet<String>,
+) -> HashMap<String, Vec<PinChange>> {
+    for _ in 0..STEPS_PER_ROUND {
+        for (device_name, dev) in devs {
+            if is_stopped(dev.borrow().state()) || held.contains(device_name) {
+                continue;
+            }
+
+            dev.borrow_mut().step();
+        }
+    }
+
+    let mut pin_changes = HashMap::new();
+
+    for (device_name, dev) in devs {
+        let mut dev = dev.borrow_mut();
+        let changes = dev.take_pin_changes();
+
+        if let Some(transceiver) = transceivers.get_mut(device_name) {
+            transceiver.update(&mut dev, &changes);
+        }
+
+        pin_changes.insert(device_name.clone(), changes);
+    }
+
+    pin_changes
+}
+
+// Follows reset lines, resetting a device when its line goes low and keeping it there until the
+// line goes high again. The line's changes are used rather than its level, so that a pulse
+// shorter than a batch still resets the device. Returns a message for each device reset.
+pub fn follow_reset_lines(
+    devs: &HashMap<String, AvrSimulatorRef>,
+    avr_configs: &HashMap<String, config::AvrDevice>,
+    pin_changes: &HashMap<String, Vec<PinChange>>,
+    held: &mut HashSet<String>,
+) -> Vec<(String, String)> {
+    let mut resets = vec![];
+
+    for (device_name, avr_config) in avr_configs {
+        let (reset_line, source, dev) = match (&avr_config.reset_line, devs.get(device_name)) {
+            (Some(reset_line), Some(dev)) => match devs.get(&reset_line.node) {
+                Some(source) => (reset_line, source, dev),
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let went_low = pin_changes.get(&reset_line.node)
+            .is_some_and(|changes| changes.iter().any(|change| {
+                change.port == reset_line.port && change.pin == reset_line.pin && !change.high
+            }));
+
+        let is_low = !source.borrow_mut().get_digital_pin(reset_line.port, reset_line.pin);
+
+        if went_low || (is_low && !held.contains(device_name)) {
+            dev.borrow_mut().reset(ResetKind::External);
+
+            let message = match is_low {
+                true => format!("{} held in reset by {}", device_name, reset_line.node),
+                false => format!("{} reset by {}", device_name, reset_line.node),
+            };
+
+            resets.push((device_name.clone(), message));
+        }
+
+        if is_low {
+            held.insert(device_name.clone());
+        } else {
+            held.remove(device_name);
+        }
+    }
+
+    resets
+}
+
+// Starts a stopped device again from its firmware, as if it had been power cycled
+pub fn restart_device(
+    device_name: &str,
+    dev: &AvrSimulatorRef,
+    avr_config: &config::AvrDevice,
+    transceivers: &mut HashMap<String, Transceiver>,
+) -> Result<(), String> {
+    *dev.borrow_mut() = new_avr(device_name, avr_config)?;
+
+    // The pins of the new device start out afresh
+    if let Some(driver_enable) = avr_config.driver_enable {
+        transceivers.insert(device_name.to_string(), Transceiver::new(driver_enable, &mut dev.borrow_mut())?);
+    }
+
+    Ok(())
+}
+
+// Takes what a device has put on the wire since the last call, along with when it sent each byte
+pub fn take_sent(device_name: &str, dev: &AvrSimulatorRef, transceivers: &mut HashMap<String, Transceiver>) -> Vec<(Duration, u8)> {
+    match transceivers.get_mut(device_name) {
+        Some(transceiver) => transceiver.take_driven(),
+        None => std::iter::from_fn(|| dev.borrow_mut().read_uart_timed('0'))
+            .map(|(at, b)| (to_duration(at), b))
+            .collect(),
+    }
+}
+
+/// A network of simulated devices, driven from Rust rather than by `mycochip up`.
+///
+/// Meant for integration tests of multi-MCU firmware: nothing listens on a port and nothing runs
+/// unless asked to, so gateways and the WebSocket server of a config are left out.
+///
+/// ```no_run
+/// # use mycochip::avr_simulator::AvrDuration;
+/// # use mycochip::simulation::Simulation;
+/// #
+/// let mut sim = Simulation::load("examples/echo_ring.yaml").unwrap();
+/// let one_second = AvrDuration::new(16_000_000, 16_000_000);
+///
+/// sim.wait_for_uart("transmitter", "hello world", one_second).unwrap();
+/// sim.wait_for_uart("node_0", "hello world", one_second).unwrap();
+///
+/// sim.send_uart("node_3", b"ping").unwrap();
+/// sim.wait_for_uart("node_3", "ping", one_second).unwrap();
+/// ```
+pub struct Simulation {
+    network: Network<'static>,
+    devs: HashMap<String, AvrSimulatorRef>,
+    avr_configs: HashMap<String, config::AvrDevice>,
+    transceivers: HashMap<String, Transceiver>,
+    held_in_reset: HashSet<String>,
+    // Set once a device whose stop policy is to halt the network has stopped
+    halted: bool,
+    // What each device has sent on the network and nobody has waited for yet
+    received: HashMap<String, Vec<u8>>,
+}
+
+impl Simulation {
+    /// Loads a config file, as `mycochip up` would, and starts its devices.
+    pub fn load(config_file_path: &str) -> Result<Self, String> {
+        let config = config::load(config_file_path, &[]).map_err(|e| e.to_string())?;
+        Self::new(config)
+    }
+
+    /// Starts the devices of a config and connects them.
+    pub fn new(mut config: MycochipConfig) -> Result<Self, String> {
+        leave_out_gateways(&mut config);
+
+        let mut network = Network::new();
+        let mut devs = HashMap::new();
+        let mut avr_configs = HashMap::new();
+        let mut transceivers = HashMap::new();
+
+        if config.routing == config::Routing::AvrNet {
+            let frame_timeout = config.frame_timeout_us.map(Duration::from_micros);
+            network.enable_routing(config.framing, frame_timeout);
+        }
+
+        for (device_name, device) in &config.devices {
+            let avr_device = match device {
+                config::Device::Avr(avr_device) => avr_device,
+                _ => continue,
+            };
+
+            let avr = Rc::new(RefCell::new(new_avr(device_name, avr_device)?));
+            check_reset_lines(device_name, &avr.borrow(), avr_device, &devs, &avr_configs)?;
+            network.create_node(device_name, AvrReceiver { avr: avr.clone() });
+
+            if let Some(address) = avr_device.address {
+                network.set_address(device_name, address)?;
+            }
+
+            if let Some(driver_enable) = avr_device.driver_enable {
+                let transceiver = Transceiver::new(driver_enable, &mut avr.borrow_mut())
+                    .map_err(|e| format!("{}: {}", device_name, e))?;
+                transceivers.insert(device_name.clone(), transceiver);
+            }
+
+            devs.insert(device_name.clone(), avr);
+            avr_configs.insert(device_name.clone(), avr_device.clone());
+        }
+
+        for (device_name, device) in &config.devices {
+            connect_device(&mut network, device_name, device)?;
+        }
+
+        configure_buses(&mut network, &config)?;
+
+        network.set_seed(config.seed);
+
+        for link in &config.links {
+            impair_configured_link(&mut network, link)?;
+        }
+
+        Ok(Self {
+            network,
+            devs,
+            avr_configs,
+            transceivers,
+            held_in_reset: HashSet::new(),
+            halted: false,
+            received: HashMap::new(),
+        })
+    }
+
+    /// Returns the simulated time of the network, i.e. that of the device furthest behind.
+    pub fn time(&self) -> Duration {
+        self.network.time()
+    }
+
+    /// Runs every device for a batch of instructions, then passes on what they have sent.
+    ///
+    /// Reset lines and stop policies are followed as by `mycochip up`.
+    pub fn step(&mut self) {
+        if self.halted {
+            return;
+        }
+
+        let pin_changes = run_batch(&self.devs, &mut self.transceivers, &self.held_in_reset);
+
+        for (device_name, dev) in &self.devs {
+            let sent = take_sent(device_name, dev, &mut self.transceivers);
+
+            if sent.is_empty() {
+                continue;
+            }
+
+            self.network.broadcast_at(device_name, &sent);
+            self.received.entry(device_name.clone()).or_default().extend(sent.iter().map(|(_, b)| *b));
+        }
+
+        follow_reset_lines(&self.devs, &self.avr_configs, &pin_changes, &mut self.held_in_reset);
+
+        for (device_name, dev) in &self.devs {
+            if !is_stopped(dev.borrow().state()) {
+                continue;
+            }
+
+            match self.avr_configs[device_name].on_stop {
+                config::StopPolicy::Ignore => {}
+                config::StopPolicy::Restart => {
+                    // A device that can't be started again, e.g. because its firmware is gone,
+                    // stays stopped
+                    let _ = restart_device(device_name, dev, &self.avr_configs[device_name], &mut self.transceivers);
+                }
+                config::StopPolicy::HaltNetwork => self.halted = true,
+            }
+        }
+
+        // Stopped devices would otherwise hold everyone else back
+        let sim_time = self.devs.values()
+            .filter(|dev| !is_stopped(dev.borrow().state()))
+            .map(sim_time_of)
+            .min();
+
+        if let Some(sim_time) = sim_time {
+            self.network.set_time(sim_time);
+        }
+
+        self.network.deliver_messages();
+    }
+
+    /// Steps the network until `duration` of simulated time has passed, or every device has
+    /// stopped.
+    pub fn run_for(&mut self, duration: AvrDuration) {
+        let end = self.time() + Duration::from_secs_f64(duration.as_secs_f64());
+
+        while self.time() < end && !self.is_stopped() {
+            self.step();
+        }
+    }
+
+    /// Steps the network until `node` has sent `pattern` on it, failing if that takes longer than
+    /// `timeout` in simulated time.
+    ///
+    /// What the node sent up to the end of the pattern is consumed, so that the next wait on it
+    /// only looks at what came after.
+    pub fn wait_for_uart(&mut self, node: &str, pattern: &str, timeout: AvrDuration) -> Result<(), String> {
+        self.device(node)?;
+
+        let deadline = self.time() + Duration::from_secs_f64(timeout.as_secs_f64());
+
+        loop {
+            let received = self.received.entry(node.to_string()).or_default();
+            let position = received.windows(pattern.len().max(1)).position(|window| window == pattern.as_bytes());
+
+            if let Some(position) = position {
+                received.drain(..position + pattern.len());
+                return Ok(());
+            }
+
+            if self.time() >= deadline || self.is_stopped() {
+                return Err(format!("{} didn't send {:?} within {}", node, pattern, timeout));
+            }
+
+            self.step();
+        }
+    }
+
+    /// Returns what `node` has sent on the network and hasn't been waited for yet.
+    pub fn received(&self, node: &str) -> &[u8] {
+        self.received.get(node).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Writes bytes to UART0 of `node`, as if they came over the network.
+    pub fn send_uart(&mut self, node: &str, data: &[u8]) -> Result<(), String> {
+        let mut dev = self.device(node)?;
+
+        for &b in data {
+            dev.write_uart('0', b);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a pin of `node` is high, e.g. `pin("main", 'B', 5)` for PB5.
+    pub fn pin(&self, node: &str, port: char, pin: u8) -> Result<bool, String> {
+        Ok(self.device_with_pin(node, port, pin)?.get_digital_pin(port, pin))
+    }
+
+    pub fn set_pin(&mut self, node: &str, port: char, pin: u8, high: bool) -> Result<(), String> {
+        self.device_with_pin(node, port, pin)?.set_digital_pin(port, pin, high);
+        Ok(())
+    }
+
+    pub fn set_analog_pin(&mut self, node: &str, pin: u8, millivolts: u32) -> Result<(), String> {
+        let mut dev = self.device(node)?;
+
+        if !dev.has_adc() {
+            return Err(format!("{} has no ADC", node));
+        }
+
+        dev.set_analog_pin(pin, millivolts);
+        Ok(())
+    }
+
+    /// Starts recording every edge of a pin of `node`, for [`Self::waveform()`].
+    pub fn monitor_pin(&mut self, node: &str, port: char, pin: u8) -> Result<(), String> {
+        match self.device(node)?.monitor_pin(port, pin) {
+            true => Ok(()),
+            false => Err(format!("{} has no pin P{}{}", node, port, pin)),
+        }
+    }
+
+    /// Returns what a monitored pin of `node` did during the last `window` of simulated time.
+    pub fn waveform(&self, node: &str, port: char, pin: u8, window: AvrDuration) -> Result<Waveform, String> {
+        self.device(node)?
+            .waveform(port, pin, window)
+            .ok_or(format!("P{}{} of {} isn't monitored", port, pin, node))
+    }
+
+    pub fn state(&self, node: &str) -> Result<AvrState, String> {
+        Ok(self.device(node)?.state())
+    }
+
+    /// Gives access to the simulator of `node`, for anything the helpers above don't cover.
+    pub fn device(&self, node: &str) -> Result<RefMut<'_, AvrSimulator>, String> {
+        self.devs.get(node)
+            .map(|dev| dev.borrow_mut())
+            .ok_or(format!("Unknown device: {}", node))
+    }
+
+    // Like `device`, but only if it has the pin, since simavr would panic about it otherwise
+    fn device_with_pin(&self, node: &str, port: char, pin: u8) -> Result<RefMut<'_, AvrSimulator>, String> {
+        let dev = self.device(node)?;
+
+        if !dev.has_pin(port, pin) {
+            return Err(format!("{} has no pin P{}{}", node, port, pin));
+        }
+
+        Ok(dev)
+    }
+
+    // Whether nothing will happen any more, which also means that time stands still
+    fn is_stopped(&self) -> bool {
+        self.halted || self.devs.values().all(|dev| is_stopped(dev.borrow().state()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateways_are_left_out() {
+        let config: MycochipConfig = serde_yaml::from_str("
+devices:
+  outside:
+    type: tcp
+    port: 5000
+    peers: [inside]
+").unwrap();
+        let mut sim = Simulation::new(config).unwrap();
+
+        assert!(sim.device("outside").is_err());
+        assert!(sim.send_uart("outside", b"hello").is_err());
+        assert!(sim.wait_for_uart("outside", "hello", AvrDuration::new(16_000_000, 16_000)).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs the firmware, built with make -C examples/uart_hello_world"]
+    fn waits_for_uart_output() {
+        let mut sim = Simulation::load("examples/uart_hello_world/uart_hello_world.yaml").unwrap();
+
+        sim.wait_for_uart("main", "hello world", AvrDuration::new(1_000_000, 1_000_000)).unwrap();
+        assert!(sim.time() > Duration::ZERO);
+
+        // The greeting has been consumed, and nothing else is ever sent
+        let error = sim.wait_for_uart("main", "goodbye", AvrDuration::new(1_000_000, 1_000)).unwrap_err();
+        assert!(error.contains("didn't send \"goodbye\""));
+
+        assert!(sim.pin("main", 'Z', 0).is_err());
+        assert!(sim.set_pin("main", 'B', 8, true).is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use serde_yaml::{Mapping, Value};
+
+// Expands device templates and arrays in a config file before it's parsed, e.g.
+//
+//   templates:
+//     sensor:
+//       mcu: atmega328p
+//       firmware: sensor.elf
+//   devices:
+//     sensor:
+//       template: sensor
+//       count: 32
+//       topology: ring
+//       address: "{i+10}"
+//       eeprom: ["{i+10}"]
+//       overrides:
+//         0:
+//           firmware: coordinator.elf
+//
+// becomes sensor_0 to sensor_31, each peered with its neighbours. In string values, `{i}` is
+// replaced by the index of the instance and `{i+K}` by the index plus K; a string that is
+// nothing but such a placeholder becomes a number.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Topology {
+    // Each instance is peered with the ones before and after it, and the last with the first
+    Ring,
+    // The first instance is peered with all others
+    Star,
+    // Like a ring, without the last and first being peered
+    Line,
+    // Every instance is peered with every other one
+    Mesh,
+}
+
+impl Topology {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "ring" => Ok(Topology::Ring),
+            "star" => Ok(Topology::Star),
+            "line" => Ok(Topology::Line),
+            "mesh" => Ok(Topology::Mesh),
+            _ => Err(format!("unknown topology: {}, expected ring, star, line or mesh", name)),
+        }
+    }
+
+    fn neighbours(self, index: u64, count: u64) -> Vec<u64> {
+        let mut neighbours: Vec<u64> = match self {
+            Topology::Ring => vec![(index + count - 1) % count, (index + 1) % count],
+            Topology::Line => vec![index.wrapping_sub(1), index + 1],
+            Topology::Star if index == 0 => (1..count).collect(),
+            Topology::Star => vec![0],
+            Topology::Mesh => (0..count).collect(),
+        };
+
+        neighbours.retain(|&other| other != index && other < count);
+        neighbours.sort();
+        neighbours.dedup();
+
+        neighbours
+    }
+}
+
+// Whether the config uses anything that `expand` has to take care of
+pub fn is_templated(config: &Value) -> bool {
+    let is_templated_device = |device: &Value| ["template", "count", "topology"].iter().any(|key| device.get(key).is_some());
+
+    config.get("templates").is_some()
+        || matches!(config.get("devices"), Some(Value::Mapping(devices)) if devices.values().any(is_templated_device))
+}
+
+pub fn expand(config: &mut Value) -> Result<(), String> {
+    let config = config.as_mapping_mut().ok_or("config must be a mapping")?;
+
+    let templates = match config.remove("templates") {
+        Some(Value::Mapping(templates)) => templates,
+        Some(_) => return Err("templates must be a mapping".to_string()),
+        None => Mapping::new(),
+    };
+
+    let devices = match config.get("devices") {
+        Some(Value::Mapping(devices)) => devices,
+        _ => return Ok(()),
+    };
+
+    let mut expanded = Mapping::new();
+
+    for (name, device) in devices {
+        let name = name.as_str().ok_or("device names must be strings")?;
+        let mut device = device.as_mapping().ok_or(format!("{}: device must be a mapping", name))?.clone();
+
+        if let Some(template_name) = device.remove("template") {
+            let template_name = template_name.as_str().ok_or(format!("{}: template must be a string", name))?;
+            let template = match templates.get(template_name) {
+                Some(Value::Mapping(template)) => template,
+                _ => return Err(format!("{}: unknown template: {}", name, template_name)),
+            };
+
+            device = merge(template, &device);
+        }
+
+        for (instance_name, instance) in instances(name, device)? {
+            if expanded.insert(Value::String(instance_name.clone()), Value::Mapping(instance)).is_some() {
+                return Err(format!("{}: device is defined twice", instance_name));
+            }
+        }
+    }
+
+    config.insert(Value::String("devices".to_string()), Value::Mapping(expanded));
+
+    Ok(())
+}
+
+// Devices described by a config entry, which is just the entry itself unless it has a count
+fn instances(name: &str, mut device: Mapping) -> Result<Vec<(String, Mapping)>, String> {
+    let count = match device.remove("count") {
+        Some(count) => count.as_u64().filter(|&count| count > 0).ok_or(format!("{}: count must be a positive number", name))?,
+        None => {
+            if device.contains_key("topology") || device.contains_key("overrides") {
+                return Err(format!("{}: topology and overrides need a count", name));
+            }
+
+            return Ok(vec![(name.to_string(), device)]);
+        }
+    };
+
+    let topology = match device.remove("topology") {
+        Some(topology) => Some(Topology::parse(topology.as_str().ok_or(format!("{}: topology must be a string", name))?)?),
+        None => None,
+    };
+
+    let overrides = match device.remove("overrides") {
+        Some(Value::Mapping(overrides)) => overrides,
+        Some(_) => return Err(format!("{}: overrides must be a mapping from index to settings", name)),
+        None => Mapping::new(),
+    };
+
+    for index in overrides.keys() {
+        if !matches!(index.as_u64(), Some(index) if index < count) {
+            return Err(format!("{}: override for an instance that doesn't exist: {:?}", name, index));
+        }
+    }
+
+    let mut instances = vec![];
+
+    for index in 0..count {
+        let mut instance = match overrides.get(Value::from(index)) {
+            Some(Value::Mapping(instance_overrides)) => merge(&device, instance_overrides),
+            Some(_) => return Err(format!("{}: override {} must be a mapping", name, index)),
+            None => device.clone(),
+        };
+
+        for value in instance.values_mut() {
+            substitute(value, index).map_err(|e| format!("{}_{}: {}", name, index, e))?;
+        }
+
+        if let Some(topology) = topology {
+            let peers = instance.entry(Value::String("peers".to_string())).or_insert(Value::Sequence(vec![]));
+            let peers = peers.as_sequence_mut().ok_or(format!("{}: peers must be a list", name))?;
+
+            for neighbour in topology.neighbours(index, count) {
+                peers.push(Value::String(format!("{}_{}", name, neighbour)));
+            }
+        }
+
+        instances.push((format!("{}_{}", name, index), instance));
+    }
+
+    Ok(instances)
+}
+
+// Settings of `base`, with those in `overrides` taking precedence
+fn merge(base: &Mapping, overrides: &Mapping) -> Mapping {
+    let mut merged = base.clone();
+
+    for (key, value) in overrides {
+        merged.insert(key.clone(), value.clone());
+    }
+
+    merged
+}
+
+// Replace placeholders for the instance index throughout a value
+fn substitute(value: &mut Value, index: u64) -> Result<(), String> {
+    match value {
+        Value::String(text) => {
+            *value = match placeholder(text, index)? {
+                Some(number) => Value::Number(number.into()),
+                None => Value::String(interpolate(text, index)?),
+            };
+        }
+        Value::Sequence(values) => {
+            for value in values {
+                substitute(value, index)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for value in mapping.values_mut() {
+                substitute(value, index)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// The number a string stands for if it's nothing but a placeholder
+fn placeholder(text: &str, index: u64) -> Result<Option<u64>, String> {
+    match text.strip_prefix('{').and_then(|rest| rest.strip_suffix('}')) {
+        Some(inner) if !inner.contains('{') => evaluate(inner, index).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn interpolate(text: &str, index: u64) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or(format!("unclosed placeholder in {}", text))? + start;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&evaluate(&rest[start + 1..end], index)?.to_string());
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+// Value of a placeholder like `i` or `i+10`
+fn evaluate(expression: &str, index: u64) -> Result<u64, String> {
+    let expression: String = expression.chars().filter(|c| !c.is_whitespace()).collect();
+
+    let offset = match expression.strip_prefix('i') {
+        Some("") => 0,
+        Some(offset) => offset.strip_prefix('+')
+            .and_then(|offset| offset.parse::<u64>().ok())
+            .ok_or(format!("invalid placeholder: {{{}}}, expected {{i}} or {{i+N}}", expression))?,
+        None => return Err(format!("invalid placeholder: {{{}}}, expected {{i}} or {{i+N}}", expression)),
+    };
+
+    Ok(index + offset)
+}
+
+// Replace `${NAME}` in string values with the environment variable NAME or, failing that, the
+// entry NAME of `vars`. A string that is nothing but such a reference takes on the type of the
+// value, so that numbers stay numbers.
+pub fn substitute_vars(value: &mut Value, vars: &Mapping) -> Result<(), String> {
+    match value {
+        Value::String(text) => {
+            let whole_name = text.strip_prefix("${")
+                .and_then(|rest| rest.strip_suffix('}'))
+                .filter(|name| !name.contains('}'));
+
+            *value = match whole_name {
+                Some(name) => lookup_var(name, vars)?,
+                None => Value::String(interpolate_vars(text, vars)?),
+            };
+        }
+        Value::Sequence(values) => {
+            for value in values {
+                substitute_vars(value, vars)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for value in mapping.values_mut() {
+                substitute_vars(value, vars)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn lookup_var(name: &str, vars: &Mapping) -> Result<Value, String> {
+    if let Ok(text) = std::env::var(name) {
+        return Ok(serde_yaml::from_str(&text).unwrap_or(Value::String(text)));
+    }
+
+    vars.get(name).cloned().ok_or(format!("unknown variable: {}", name))
+}
+
+fn interpolate_vars(text: &str, vars: &Mapping) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}').ok_or(format!("unclosed variable in {}", text))? + start;
+        let name = &rest[start + 2..end];
+
+        let value = match lookup_var(name, vars)? {
+            Value::String(value) => value,
+            Value::Number(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            _ => return Err(format!("variable {} can't be used inside text", name)),
+        };
+
+        result.push_str(&rest[..start]);
+        result.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(yaml: &str) -> Value {
+        let mut config: Value = serde_yaml::from_str(yaml).unwrap();
+        expand(&mut config).unwrap();
+        config["devices"].clone()
+    }
+
+    #[test]
+    fn instances_are_numbered_and_overridden() {
+        let devices = expanded("
+templates:
+  sensor:
+    mcu: atmega328p
+    firmware: sensor.elf
+devices:
+  sensor:
+    template: sensor
+    count: 3
+    address: '{i+10}'
+    eeprom: ['{i+10}']
+    overrides:
+      1:
+        firmware: sensor_{i}.elf
+");
+
+        assert_eq!(devices.as_mapping().unwrap().len(), 3);
+        assert_eq!(devices["sensor_2"]["mcu"], Value::from("atmega328p"));
+        assert_eq!(devices["sensor_2"]["address"], Value::from(12));
+        assert_eq!(devices["sensor_2"]["eeprom"][0], Value::from(12));
+        assert_eq!(devices["sensor_0"]["firmware"], Value::from("sensor.elf"));
+        assert_eq!(devices["sensor_1"]["firmware"], Value::from("sensor_1.elf"));
+    }
+
+    #[test]
+    fn topologies_peer_instances() {
+        assert_eq!(Topology::Ring.neighbours(0, 4), [1, 3]);
+        assert_eq!(Topology::Ring.neighbours(0, 2), [1]);
+        assert_eq!(Topology::Line.neighbours(0, 4), [1]);
+        assert_eq!(Topology::Line.neighbours(3, 4), [2]);
+        assert_eq!(Topology::Star.neighbours(0, 4), [1, 2, 3]);
+        assert_eq!(Topology::Star.neighbours(2, 4), [0]);
+        assert_eq!(Topology::Mesh.neighbours(1, 3), [0, 2]);
+
+        let devices = expanded("
+devices:
+  node:
+    mcu: atmega328p
+    firmware: node.elf
+    count: 3
+    topology: line
+    peers: [gateway]
+");
+
+        let peers: Vec<Value> = vec!["gateway".into(), "node_0".into(), "node_2".into()];
+        assert_eq!(devices["node_1"]["peers"], Value::Sequence(peers));
+    }
+
+    #[test]
+    fn variables_are_substituted() {
+        let vars: Mapping = serde_yaml::from_str("build: debug\naddress: 7\n").unwrap();
+        let mut device: Value = serde_yaml::from_str("firmware: build/${build}/main.elf\naddress: ${address}\n").unwrap();
+
+        substitute_vars(&mut device, &vars).unwrap();
+
+        assert_eq!(device["firmware"], Value::from("build/debug/main.elf"));
+        assert_eq!(device["address"], Value::from(7));
+
+        let mut device: Value = serde_yaml::from_str("firmware: ${MYCOCHIP_NO_SUCH_VARIABLE}\n").unwrap();
+        assert!(substitute_vars(&mut device, &vars).is_err());
+    }
+
+    #[test]
+    fn names_must_be_unique() {
+        let mut config: Value = serde_yaml::from_str("
+devices:
+  node_1:
+    mcu: atmega328p
+    firmware: node.elf
+  node:
+    mcu: atmega328p
+    firmware: node.elf
+    count: 2
+").unwrap();
+
+        assert!(expand(&mut config).is_err());
+    }
+
+    #[test]
+    fn counts_must_be_positive() {
+        for count in ["0", "-1", "two"] {
+            let mut config: Value = serde_yaml::from_str(&format!("
+devices:
+  node:
+    mcu: atmega328p
+    firmware: node.elf
+    count: {}
+", count)).unwrap();
+
+            assert!(expand(&mut config).is_err(), "count: {}", count);
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+// Appended to the client's key to prove that the server speaks WebSocket, from RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Commands are small, so anything bigger is a confused or hostile client
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// A dashboard that can't keep up with the events is dropped instead of holding them all
+const MAX_PENDING_SIZE: usize = 1024 * 1024;
+
+// How long a client may take to receive the last events when the server closes
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// What the network tells dashboards, mirroring what is published over ZMQ
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Bus { node: String, data: Vec<u8> },
+    Pin { node: String, port: char, pin: u8, state: bool },
+    Error { message: String },
+    // The network is going away, with the devices that crashed along the way
+    Shutdown { crashed: Vec<String> },
+}
+
+// What dashboards can ask the network to do
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    // Bytes for a device to receive on one of its UARTs
+    Uart {
+        node: String,
+        #[serde(default = "default_uart")]
+        uart: char,
+        data: Vec<u8>,
+    },
+    Pin { node: String, port: char, pin: u8, state: bool },
+}
+
+fn default_uart() -> char {
+    '0'
+}
+
+enum ClientState {
+    // Waiting for the HTTP upgrade request
+    Handshake,
+    Open,
+    Closed,
+}
+
+struct Client {
+    id: u64,
+    stream: TcpStream,
+    state: ClientState,
+    rx_buffer: Vec<u8>,
+    tx_buffer: Vec<u8>,
+    // Payload of a message that is split across frames
+    fragments: Vec<u8>,
+}
+
+impl Client {
+    fn new(id: u64, stream: TcpStream) -> Self {
+        Self {
+            id,
+            stream,
+            state: ClientState::Handshake,
+            rx_buffer: Vec::new(),
+            tx_buffer: Vec::new(),
+            fragments: Vec::new(),
+        }
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) {
+        if self.tx_buffer.len() + payload.len() > MAX_PENDING_SIZE {
+            println!("WebSocket client {} is too slow, disconnecting", self.id);
+            self.state = ClientState::Closed;
+            return;
+        }
+
+        self.tx_buffer.extend(encode_frame(opcode, payload));
+    }
+
+    // Read what has arrived and return the complete text messages
+    fn receive(&mut self) -> Vec<String> {
+        let mut buf = [0; 4096];
+
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.state = ClientState::Closed;
+                    break;
+                }
+                Ok(len) => self.rx_buffer.extend(&buf[..len]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.state = ClientState::Closed;
+                    break;
+                }
+            }
+        }
+
+        if let ClientState::Handshake = self.state {
+            self.handshake();
+        }
+
+        let mut messages = vec![];
+
+        while let ClientState::Open = self.state {
+            let (frame, frame_len) = match decode_frame(&self.rx_buffer) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => break,
+                Err(err) => {
+                    println!("WebSocket client {}: {}", self.id, err);
+                    self.state = ClientState::Closed;
+                    break;
+                }
+            };
+
+            self.rx_buffer.drain(..frame_len);
+
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    self.fragments.extend(&frame.payload);
+
+                    if self.fragments.len() > MAX_MESSAGE_SIZE {
+                        println!("WebSocket client {} sent an oversize message", self.id);
+                        self.state = ClientState::Closed;
+                    } else if frame.fin {
+                        let message = std::mem::take(&mut self.fragments);
+                        messages.push(String::from_utf8_lossy(&message).into_owned());
+                    }
+                }
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &frame.payload),
+                OPCODE_CLOSE => {
+                    self.send_frame(OPCODE_CLOSE, &[]);
+                    self.flush();
+                    self.state = ClientState::Closed;
+                }
+                _ => {}
+            }
+        }
+
+        messages
+    }
+
+    fn handshake(&mut self) {
+        let end = match self.rx_buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end + 4,
+            None => {
+                if self.rx_buffer.len() > MAX_MESSAGE_SIZE {
+                    self.state = ClientState::Closed;
+                }
+
+                return;
+            }
+        };
+
+        let request = String::from_utf8_lossy(&self.rx_buffer[..end]).into_owned();
+        self.rx_buffer.drain(..end);
+
+        let key = match upgrade_key(&request) {
+            Some(key) => key,
+            None => {
+                let _ = self.stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+                self.state = ClientState::Closed;
+                return;
+            }
+        };
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key),
+        );
+
+        self.tx_buffer.extend(response.as_bytes());
+        self.state = ClientState::Open;
+    }
+
+    fn flush(&mut self) {
+        while !self.tx_buffer.is_empty() {
+            match self.stream.write(&self.tx_buffer) {
+                Ok(0) => {
+                    self.state = ClientState::Closed;
+                    break;
+                }
+                Ok(len) => {
+                    self.tx_buffer.drain(..len);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.state = ClientState::Closed;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Serves events to browser dashboards and takes commands from them. It is driven from the main
+// loop instead of its own threads, so nothing is shared with the simulation.
+pub struct WebSocketServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    next_client_id: u64,
+}
+
+impl WebSocketServer {
+    pub fn bind(port: u16) -> Result<Self, io::Error> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            clients: Vec::new(),
+            next_client_id: 0,
+        })
+    }
+
+    // Accept new clients and return the commands that have arrived, with the id of their sender
+    pub fn poll(&mut self) -> Vec<(u64, Result<Command, String>)> {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+
+            self.clients.push(Client::new(self.next_client_id, stream));
+            self.next_client_id += 1;
+        }
+
+        let mut commands = vec![];
+
+        for client in self.clients.iter_mut() {
+            for message in client.receive() {
+                let command = serde_json::from_str(&message)
+                    .map_err(|e| format!("Invalid command: {}", e));
+
+                commands.push((client.id, command));
+            }
+
+            client.flush();
+        }
+
+        self.clients.retain(|client| !matches!(client.state, ClientState::Closed));
+
+        commands
+    }
+
+    pub fn broadcast(&mut self, event: &Event) {
+        let json = serde_json::to_string(event).unwrap();
+
+        for client in self.clients.iter_mut() {
+            if let ClientState::Open = client.state {
+                client.send_frame(OPCODE_TEXT, json.as_bytes());
+                client.flush();
+            }
+        }
+    }
+
+    // Say goodbye to every client and send what is still queued for them
+    pub fn close(&mut self) {
+        for client in self.clients.iter_mut() {
+            if let ClientState::Open = client.state {
+                client.send_frame(OPCODE_CLOSE, &[]);
+            }
+
+            // Sockets are non-blocking, so wait a little for slow clients to take the last events
+            let _ = client.stream.set_nonblocking(false);
+            let _ = client.stream.set_write_timeout(Some(CLOSE_TIMEOUT));
+            client.flush();
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+
+        self.clients.clear();
+    }
+
+    pub fn send_to(&mut self, client_id: u64, event: &Event) {
+        let json = serde_json::to_string(event).unwrap();
+
+        if let Some(client) = self.clients.iter_mut().find(|client| client.id == client_id) {
+            client.send_frame(OPCODE_TEXT, json.as_bytes());
+            client.flush();
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Decode a frame from a client, returning it along with how many bytes it took up, or None if
+// the frame hasn't fully arrived yet
+fn decode_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, &'static str> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+
+    if !masked {
+        return Err("Frames from clients must be masked");
+    }
+
+    let (payload_len, mut idx) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize, 10),
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+
+    if payload_len > MAX_MESSAGE_SIZE {
+        return Err("Frame too large");
+    }
+
+    if buf.len() < idx + 4 + payload_len {
+        return Ok(None);
+    }
+
+    let mask = &buf[idx..idx + 4];
+    idx += 4;
+
+    let payload = buf[idx..idx + payload_len].iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, idx + payload_len)))
+}
+
+// Encode an unfragmented frame from the server, which is never masked
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend(payload);
+    frame
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+// The key of a request to upgrade to a WebSocket, or None if it's not one
+fn upgrade_key(request: &str) -> Option<String> {
+    let mut lines = request.lines();
+
+    if !lines.next()?.starts_with("GET ") {
+        return None;
+    }
+
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+
+    let header = |wanted: &str| headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| *value);
+
+    if !header("Upgrade")?.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+
+    header("Sec-WebSocket-Key").map(str::to_string)
+}
+
+// SHA-1 is only needed for the handshake, so it's not worth a dependency
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (wi, word) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *wi = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (hi, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hi = hi.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (i, hi) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&hi.to_be_bytes());
+    }
+
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::{accept_key, decode_frame, encode_frame, upgrade_key, Command, Event, OPCODE_TEXT};
+
+    #[test]
+    fn accept_key_matches_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn only_websocket_upgrades_are_accepted() {
+        let request = "GET /events HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: abc==\r\n\r\n";
+        assert_eq!(upgrade_key(request), Some("abc==".to_string()));
+
+        assert_eq!(upgrade_key(&request.replace("GET", "POST")), None);
+        assert_eq!(upgrade_key(&request.replace("Upgrade: websocket\r\n", "")), None);
+        assert_eq!(upgrade_key(&request.replace("websocket", "h2c")), None);
+    }
+
+    #[test]
+    fn decodes_masked_frame() {
+        // "Hello" from the examples in RFC 6455
+        let buf = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (frame, len) = decode_frame(&buf).unwrap().unwrap();
+
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+        assert_eq!(len, buf.len());
+
+        assert!(decode_frame(&buf[..buf.len() - 1]).unwrap().is_none());
+    }
+
+    #[test]
+    fn encodes_long_frame() {
+        let frame = encode_frame(OPCODE_TEXT, &[0; 300]);
+        assert_eq!(&frame[..4], &[0x81, 126, 0x01, 0x2c]);
+        assert_eq!(frame.len(), 304);
+    }
+
+    #[test]
+    fn events_and_commands_are_json() {
+        let event = Event::Pin { node: "main".to_string(), port: 'B', pin: 5, state: true };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"pin","node":"main","port":"B","pin":5,"state":true}"#,
+        );
+
+        let command: Command = serde_json::from_str(r#"{"type":"uart","node":"main","data":[104,105]}"#).unwrap();
+        assert_eq!(command, Command::Uart { node: "main".to_string(), uart: '0', data: vec![104, 105] });
+    }
+}